@@ -0,0 +1,440 @@
+use std::fmt::Write;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use crate::options::{ContainerSelector, ConversionOptions};
+
+/// Knows where to look for the binaries which were mapped into the recorded
+/// processes.
+///
+/// The file at the recorded path is not always the binary which was mapped:
+/// the profile may be converted on a different machine, or a package upgrade
+/// may have replaced the binary since the recording. So there are multiple
+/// places to look, and the caller should use the first file whose build ID
+/// matches.
+#[derive(Debug, Clone, Default)]
+pub struct BinaryLookup {
+    /// A copy of the recording machine's root filesystem, which the recorded
+    /// paths are relative to.
+    sysroot: Option<PathBuf>,
+    extra_dir: Option<PathBuf>,
+    /// The root of perf's build ID cache, usually `~/.debug`.
+    build_id_cache_dir: Option<PathBuf>,
+    /// A debuginfod client cache, usually `~/.cache/debuginfod_client`.
+    debuginfod_cache_dir: Option<PathBuf>,
+    /// The root filesystems of containers, for processes whose mapped paths
+    /// are relative to a container's root.
+    container_roots: Vec<(ContainerSelector, PathBuf)>,
+}
+
+/// Where the files of a recorded process are, in case it ran in a container.
+#[derive(Debug, Clone)]
+pub struct ProcessRoot {
+    pid: i32,
+    /// The root directory from `ConversionOptions::container_roots`.
+    mapped_root: Option<PathBuf>,
+}
+
+impl BinaryLookup {
+    pub fn new(options: &ConversionOptions) -> Self {
+        Self {
+            sysroot: options.sysroot.clone(),
+            extra_dir: options.extra_dir.clone(),
+            build_id_cache_dir: options
+                .build_id_cache_dir
+                .clone()
+                .or_else(default_build_id_cache_dir),
+            debuginfod_cache_dir: options
+                .debuginfod_cache_dir
+                .clone()
+                .or_else(default_debuginfod_cache_dir),
+            container_roots: options.container_roots.clone(),
+        }
+    }
+
+    /// Find the root directory of the process `pid` among the container roots
+    /// which were given by pid or by cgroup. The cgroup of a process can only
+    /// be found while the process is running.
+    pub fn process_root(&self, pid: i32) -> ProcessRoot {
+        self.process_root_in_cgroups(pid, || read_cgroups(pid))
+    }
+
+    /// Like `process_root`, but with the cgroups of the process coming from
+    /// `read_cgroups`, which is only called if a container root was given by
+    /// cgroup.
+    fn process_root_in_cgroups(
+        &self,
+        pid: i32,
+        read_cgroups: impl Fn() -> Vec<String>,
+    ) -> ProcessRoot {
+        let mut cgroups = None;
+        let mapped_root = self
+            .container_roots
+            .iter()
+            .find(|(selector, _)| match selector {
+                ContainerSelector::Pid(selector_pid) => *selector_pid == pid,
+                ContainerSelector::Cgroup(cgroup) => cgroups
+                    .get_or_insert_with(&read_cgroups)
+                    .iter()
+                    .any(|process_cgroup| Path::new(process_cgroup).starts_with(cgroup)),
+            })
+            .map(|(_, root)| root.clone());
+        ProcessRoot { pid, mapped_root }
+    }
+
+    /// The paths at which the binary which was mapped from `path` may be
+    /// found, in the order in which they should be tried. The copies in the
+    /// build ID cache can only be found if the build ID is known.
+    pub fn candidate_paths(
+        &self,
+        path: &Path,
+        build_id: Option<&[u8]>,
+        process_root: Option<&ProcessRoot>,
+    ) -> Vec<PathBuf> {
+        let mut paths = self.paths_in_process_root(path, process_root);
+        if let (Some(extra_dir), Some(filename)) = (&self.extra_dir, path.file_name()) {
+            paths.push(extra_dir.join(filename));
+        }
+        if let (Some(cache_dir), Some(build_id)) = (&self.build_id_cache_dir, build_id) {
+            paths.extend(build_id_cache_paths(cache_dir, path, build_id));
+        }
+        if let (Some(cache_dir), Some(build_id)) = (&self.debuginfod_cache_dir, build_id) {
+            paths.push(cache_dir.join(build_id_to_hex(build_id)).join("executable"));
+        }
+        paths
+    }
+
+    /// The paths at which the separate debug file of the binary which was
+    /// mapped from `path` may be found, in the order in which they should be
+    /// tried. Debug files are found by the binary's build ID, or by the file
+    /// name from its `.gnu_debuglink` section. Like the binary, they may be in
+    /// the root filesystem of the process's container.
+    pub fn debug_file_candidate_paths(
+        &self,
+        path: &Path,
+        build_id: Option<&[u8]>,
+        debuglink: Option<&str>,
+        process_root: Option<&ProcessRoot>,
+    ) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        if let Some(build_id) = build_id {
+            let build_id = build_id_to_hex(build_id);
+            if let Some(cache_dir) = &self.debuginfod_cache_dir {
+                paths.push(cache_dir.join(&build_id).join("debuginfo"));
+            }
+            if build_id.len() > 2 {
+                let (dir, rest) = build_id.split_at(2);
+                if let Some(cache_dir) = &self.build_id_cache_dir {
+                    paths.push(
+                        cache_dir
+                            .join(".build-id")
+                            .join(dir)
+                            .join(rest)
+                            .join("debug"),
+                    );
+                }
+                let debug_path = Path::new("/usr/lib/debug/.build-id")
+                    .join(dir)
+                    .join(format!("{}.debug", rest));
+                paths.extend(self.paths_in_process_root(&debug_path, process_root));
+            }
+        }
+        if let (Some(debuglink), Some(dir)) = (debuglink, path.parent()) {
+            // The places in which gdb looks for the file named in .gnu_debuglink.
+            let relative_dir = dir.strip_prefix("/").unwrap_or(dir);
+            for debug_path in [
+                dir.join(debuglink),
+                dir.join(".debug").join(debuglink),
+                Path::new("/usr/lib/debug")
+                    .join(relative_dir)
+                    .join(debuglink),
+            ] {
+                paths.extend(self.paths_in_process_root(&debug_path, process_root));
+            }
+        }
+        paths
+    }
+
+    /// Open the file which was at `path` on the recording machine, e.g. a
+    /// jitdump file, for which there is no build ID to check.
+    pub fn open(&self, path: &Path, process_root: Option<&ProcessRoot>) -> std::io::Result<File> {
+        let mut first_err = None;
+        for candidate in self.candidate_paths(path, None, process_root) {
+            match File::open(candidate) {
+                Ok(file) => return Ok(file),
+                Err(err) => {
+                    first_err.get_or_insert(err);
+                }
+            }
+        }
+        Err(first_err.unwrap())
+    }
+
+    /// Where the file which was at `path` in the filesystem of a recorded
+    /// process may be on this machine. If the process ran in a container,
+    /// the path is relative to the container's root, which is found through
+    /// `/proc/<pid>/root` while the process is running, or else through
+    /// `process_root`'s mapped root.
+    fn paths_in_process_root(
+        &self,
+        path: &Path,
+        process_root: Option<&ProcessRoot>,
+    ) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        if let Some(process_root) = process_root {
+            let relative_path = path.strip_prefix("/").unwrap_or(path);
+            // With a sysroot, the profile is converted on another machine, so
+            // the local /proc doesn't have the recorded processes.
+            if self.sysroot.is_none() {
+                paths.push(
+                    Path::new("/proc")
+                        .join(process_root.pid.to_string())
+                        .join("root")
+                        .join(relative_path),
+                );
+            }
+            if let Some(mapped_root) = &process_root.mapped_root {
+                paths.push(mapped_root.join(relative_path));
+            }
+        }
+        paths.push(self.path_in_sysroot(path));
+        paths
+    }
+
+    /// Where the file which was at `path` on the recording machine is on this
+    /// machine, if it's anywhere.
+    pub fn path_in_sysroot(&self, path: &Path) -> PathBuf {
+        match &self.sysroot {
+            Some(sysroot) => sysroot.join(path.strip_prefix("/").unwrap_or(path)),
+            None => path.to_owned(),
+        }
+    }
+}
+
+/// Read the cgroup paths of a running process from `/proc/<pid>/cgroup`.
+fn read_cgroups(pid: i32) -> Vec<String> {
+    std::fs::read_to_string(format!("/proc/{}/cgroup", pid))
+        .map(|contents| parse_cgroups(&contents))
+        .unwrap_or_default()
+}
+
+/// Each line of `/proc/<pid>/cgroup` is `<hierarchy id>:<controllers>:<path>`.
+/// With cgroup v2, there's a single line starting with `0::`.
+fn parse_cgroups(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .filter_map(|line| line.splitn(3, ':').nth(2))
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
+/// Like the debuginfod client, use `$DEBUGINFOD_CACHE_PATH`, or
+/// `debuginfod_client` in `$XDG_CACHE_HOME` or `~/.cache`.
+fn default_debuginfod_cache_dir() -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os("DEBUGINFOD_CACHE_PATH") {
+        return Some(PathBuf::from(dir));
+    }
+    let cache_home = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new(&std::env::var_os("HOME")?).join(".cache"),
+    };
+    Some(cache_home.join("debuginfod_client"))
+}
+
+/// Like perf, use `$PERF_BUILDID_DIR` or `~/.debug`.
+fn default_build_id_cache_dir() -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os("PERF_BUILDID_DIR") {
+        return Some(PathBuf::from(dir));
+    }
+    std::env::var_os("HOME").map(|home| Path::new(&home).join(".debug"))
+}
+
+/// `perf record` and `perf buildid-cache` store a copy of each binary at
+/// `<dir>/<path>/<build id>/elf`, and link to it from
+/// `<dir>/.build-id/<first two hex digits>/<remaining hex digits>/elf`.
+fn build_id_cache_paths(cache_dir: &Path, path: &Path, build_id: &[u8]) -> Vec<PathBuf> {
+    let build_id = build_id_to_hex(build_id);
+    let mut paths = Vec::new();
+    if build_id.len() > 2 {
+        let (dir, rest) = build_id.split_at(2);
+        paths.push(cache_dir.join(".build-id").join(dir).join(rest).join("elf"));
+    }
+    if path.is_absolute() {
+        let relative_path = path.strip_prefix("/").unwrap_or(path);
+        paths.push(cache_dir.join(relative_path).join(&build_id).join("elf"));
+    }
+    paths
+}
+
+/// The lowercase hex string of the build ID, which is how perf names the
+/// directories in its build ID cache.
+pub fn build_id_to_hex(build_id: &[u8]) -> String {
+    let mut s = String::with_capacity(build_id.len() * 2);
+    for byte in build_id {
+        let _ = write!(s, "{:02x}", byte);
+    }
+    s
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_cgroups, BinaryLookup, ProcessRoot};
+    use crate::options::ContainerSelector;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn candidate_paths() {
+        let mut lookup = BinaryLookup {
+            sysroot: None,
+            extra_dir: Some(PathBuf::from("/extra")),
+            build_id_cache_dir: Some(PathBuf::from("/home/me/.debug")),
+            debuginfod_cache_dir: None,
+            container_roots: Vec::new(),
+        };
+        let path = Path::new("/usr/lib/libc.so.6");
+        assert_eq!(
+            lookup.candidate_paths(path, Some(&[0xab, 0xcd, 0x01, 0x2f]), None),
+            [
+                "/usr/lib/libc.so.6",
+                "/extra/libc.so.6",
+                "/home/me/.debug/.build-id/ab/cd012f/elf",
+                "/home/me/.debug/usr/lib/libc.so.6/abcd012f/elf",
+            ]
+            .map(PathBuf::from)
+        );
+        assert_eq!(
+            lookup.candidate_paths(path, None, None),
+            ["/usr/lib/libc.so.6", "/extra/libc.so.6"].map(PathBuf::from)
+        );
+
+        // Processes in containers are looked at through /proc while they're
+        // running, and through the given root directory afterwards.
+        let process_root = ProcessRoot {
+            pid: 1234,
+            mapped_root: Some(PathBuf::from("/containers/app")),
+        };
+        assert_eq!(
+            lookup.candidate_paths(path, None, Some(&process_root)),
+            [
+                "/proc/1234/root/usr/lib/libc.so.6",
+                "/containers/app/usr/lib/libc.so.6",
+                "/usr/lib/libc.so.6",
+                "/extra/libc.so.6"
+            ]
+            .map(PathBuf::from)
+        );
+
+        // The sysroot replaces the local root filesystem.
+        lookup.sysroot = Some(PathBuf::from("/mnt/prod-rootfs"));
+        assert_eq!(
+            lookup.candidate_paths(path, None, None),
+            ["/mnt/prod-rootfs/usr/lib/libc.so.6", "/extra/libc.so.6"].map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn debug_file_candidate_paths() {
+        let lookup = BinaryLookup {
+            build_id_cache_dir: Some(PathBuf::from("/home/me/.debug")),
+            debuginfod_cache_dir: Some(PathBuf::from("/debuginfod")),
+            ..Default::default()
+        };
+        let path = Path::new("/usr/bin/app");
+        assert_eq!(
+            lookup.candidate_paths(path, Some(&[0xab, 0xcd, 0xef]), None)[3..],
+            [PathBuf::from("/debuginfod/abcdef/executable")]
+        );
+        assert_eq!(
+            lookup.debug_file_candidate_paths(
+                path,
+                Some(&[0xab, 0xcd, 0xef]),
+                Some("app.debug"),
+                None
+            ),
+            [
+                "/debuginfod/abcdef/debuginfo",
+                "/home/me/.debug/.build-id/ab/cdef/debug",
+                "/usr/lib/debug/.build-id/ab/cdef.debug",
+                "/usr/bin/app.debug",
+                "/usr/bin/.debug/app.debug",
+                "/usr/lib/debug/usr/bin/app.debug",
+            ]
+            .map(PathBuf::from)
+        );
+        assert!(lookup
+            .debug_file_candidate_paths(path, None, None, None)
+            .is_empty());
+
+        // Debug files which were installed in a container are in its root.
+        let process_root = ProcessRoot {
+            pid: 1234,
+            mapped_root: Some(PathBuf::from("/containers/app")),
+        };
+        assert_eq!(
+            lookup.debug_file_candidate_paths(path, None, Some("app.debug"), Some(&process_root))
+                [..3],
+            [
+                "/proc/1234/root/usr/bin/app.debug",
+                "/containers/app/usr/bin/app.debug",
+                "/usr/bin/app.debug",
+            ]
+            .map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn container_root_by_pid() {
+        let lookup = BinaryLookup {
+            container_roots: vec![(ContainerSelector::Pid(42), PathBuf::from("/rootfs"))],
+            ..Default::default()
+        };
+        assert_eq!(
+            lookup.process_root(42).mapped_root,
+            Some(PathBuf::from("/rootfs"))
+        );
+        assert_eq!(lookup.process_root(43).mapped_root, None);
+    }
+
+    #[test]
+    fn container_root_by_cgroup() {
+        let cgroups = parse_cgroups("12:pids:/docker/0123abcd\n0::/docker/0123abcd/app\n");
+        assert_eq!(cgroups, ["/docker/0123abcd", "/docker/0123abcd/app"]);
+
+        let lookup = BinaryLookup {
+            container_roots: vec![
+                (ContainerSelector::Pid(7), PathBuf::from("/other")),
+                (
+                    ContainerSelector::Cgroup("/docker/0123abcd".to_string()),
+                    PathBuf::from("/rootfs"),
+                ),
+            ],
+            ..Default::default()
+        };
+        let root_in = |cgroups: &[&str]| {
+            let cgroups: Vec<String> = cgroups.iter().map(|s| s.to_string()).collect();
+            lookup
+                .process_root_in_cgroups(42, || cgroups.clone())
+                .mapped_root
+        };
+        // A process in the cgroup, or in a cgroup below it, is in the container.
+        assert_eq!(
+            root_in(&["/docker/0123abcd"]),
+            Some(PathBuf::from("/rootfs"))
+        );
+        assert_eq!(
+            root_in(&["/docker/0123abcd/app"]),
+            Some(PathBuf::from("/rootfs"))
+        );
+        // Only whole path components match.
+        assert_eq!(root_in(&["/docker/0123abcdef"]), None);
+        assert_eq!(root_in(&["/docker"]), None);
+        assert_eq!(root_in(&[]), None);
+        // The pid selector is tried first.
+        assert_eq!(
+            lookup
+                .process_root_in_cgroups(7, || cgroups.clone())
+                .mapped_root,
+            Some(PathBuf::from("/other"))
+        );
+    }
+}
@@ -0,0 +1,127 @@
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use linux_perf_data::{Endianness, Feature, PerfFile};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The contents of the `HEADER_CLOCK_DATA` feature section.
+///
+/// Newer versions of perf record a pair of timestamps taken at the same moment:
+/// one from the clock which is used for the sample timestamps (usually
+/// `CLOCK_MONOTONIC`, or whatever was requested with `perf record -k`), and one
+/// from the wall clock ("time of day"). This lets us map sample timestamps to
+/// wall-clock time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockData {
+    /// The clock ID of the clock which was used for sample timestamps, e.g.
+    /// 1 for `CLOCK_MONOTONIC`.
+    pub clockid: u32,
+    /// The wall-clock time at the reference moment, in nanoseconds since the
+    /// Unix epoch.
+    pub wall_clock_ns: u64,
+    /// The value of the sample clock at the reference moment, in nanoseconds.
+    pub clockid_time_ns: u64,
+}
+
+impl ClockData {
+    pub const STRUCT_SIZE: usize = 4 + 4 + 8 + 8;
+
+    pub fn from_perf_file(perf_file: &PerfFile) -> Option<Self> {
+        let data = perf_file.feature_section_data(Feature::CLOCK_DATA)?;
+        match perf_file.endian() {
+            Endianness::LittleEndian => Self::parse::<LittleEndian>(data),
+            Endianness::BigEndian => Self::parse::<BigEndian>(data),
+        }
+    }
+
+    pub fn parse<T: ByteOrder>(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::STRUCT_SIZE {
+            return None;
+        }
+        let version = T::read_u32(&data[0..4]);
+        if version != 1 {
+            return None;
+        }
+        Some(Self {
+            clockid: T::read_u32(&data[4..8]),
+            wall_clock_ns: T::read_u64(&data[8..16]),
+            clockid_time_ns: T::read_u64(&data[16..24]),
+        })
+    }
+
+    /// Convert a sample timestamp into the wall-clock time at which the sample was taken.
+    pub fn wall_clock_time(&self, timestamp_ns: u64) -> SystemTime {
+        let reference = UNIX_EPOCH + Duration::from_nanos(self.wall_clock_ns);
+        if timestamp_ns >= self.clockid_time_ns {
+            reference + Duration::from_nanos(timestamp_ns - self.clockid_time_ns)
+        } else {
+            reference - Duration::from_nanos(self.clockid_time_ns - timestamp_ns)
+        }
+    }
+}
+
+/// Compute the wall-clock time at which the first sample was taken.
+///
+/// We prefer the clock data from the perf.data file. If it's not present, we
+/// use the modification time of the file: perf writes the file until the end of
+/// the recording, so the mtime is roughly the time of the last sample.
+pub fn recording_start_time(
+    clock_data: Option<&ClockData>,
+    file_mtime: Option<SystemTime>,
+    first_sample_time: u64,
+    last_sample_time: u64,
+) -> Option<SystemTime> {
+    if let Some(clock_data) = clock_data {
+        return Some(clock_data.wall_clock_time(first_sample_time));
+    }
+    let recording_duration =
+        Duration::from_nanos(last_sample_time.saturating_sub(first_sample_time));
+    file_mtime.and_then(|mtime| mtime.checked_sub(recording_duration))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{recording_start_time, ClockData};
+    use byteorder::LittleEndian;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn parse_clock_data() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&1_650_000_000_000_000_000u64.to_le_bytes());
+        data.extend_from_slice(&5_000_000_000u64.to_le_bytes());
+        let clock_data = ClockData::parse::<LittleEndian>(&data).unwrap();
+        assert_eq!(
+            clock_data,
+            ClockData {
+                clockid: 1,
+                wall_clock_ns: 1_650_000_000_000_000_000,
+                clockid_time_ns: 5_000_000_000,
+            }
+        );
+
+        let reference = UNIX_EPOCH + Duration::from_nanos(1_650_000_000_000_000_000);
+        assert_eq!(
+            clock_data.wall_clock_time(7_000_000_000),
+            reference + Duration::from_secs(2)
+        );
+        assert_eq!(
+            clock_data.wall_clock_time(4_000_000_000),
+            reference - Duration::from_secs(1)
+        );
+
+        // Unknown versions are rejected.
+        data[0] = 2;
+        assert_eq!(ClockData::parse::<LittleEndian>(&data), None);
+    }
+
+    #[test]
+    fn start_time_from_mtime() {
+        let mtime = UNIX_EPOCH + Duration::from_secs(1000);
+        assert_eq!(
+            recording_start_time(None, Some(mtime), 3_000_000_000, 10_000_000_000),
+            Some(UNIX_EPOCH + Duration::from_secs(993))
+        );
+        assert_eq!(recording_start_time(None, None, 0, 0), None);
+    }
+}
@@ -33,6 +33,13 @@
 /// Does the accumulated time cross an "off-cpu sampling" threshold?
 /// If yes, turn it into an off-cpu sampling group and consume a multiple of the interval.
 /// If no, don't emit any samples. The next sample's cpu delta will just be smaller.
+///
+/// ## Preempted vs. blocked
+///
+/// A thread which was switched out while it was still runnable was preempted; it was
+/// waiting for a CPU. Otherwise it was blocked, for example on a lock, on I/O or in a
+/// sleep. The off-cpu time is accumulated separately for these two reasons, and each
+/// off-cpu sample group is for one of them.
 pub struct ContextSwitchHandler {
     off_cpu_sampling_interval_ns: u64,
 }
@@ -44,7 +51,12 @@ impl ContextSwitchHandler {
         }
     }
 
-    pub fn handle_switch_out(&self, timestamp: u64, thread: &mut ThreadContextSwitchData) {
+    pub fn handle_switch_out(
+        &self,
+        timestamp: u64,
+        reason: OffCpuReason,
+        thread: &mut ThreadContextSwitchData,
+    ) {
         match &thread.state {
             ThreadState::Unknown => {
                 // This "switch-out" is the first time we've heard of the thread. So it must
@@ -53,6 +65,7 @@ impl ContextSwitchHandler {
                 // Just store the new state.
                 thread.state = ThreadState::Off {
                     off_switch_timestamp: timestamp,
+                    reason,
                 };
             }
 
@@ -67,6 +80,7 @@ impl ContextSwitchHandler {
 
                 thread.state = ThreadState::Off {
                     off_switch_timestamp: timestamp,
+                    reason,
                 };
             }
             ThreadState::Off { .. } => {
@@ -104,16 +118,17 @@ impl ContextSwitchHandler {
             }
             ThreadState::Off {
                 off_switch_timestamp,
+                reason,
             } => {
                 // The thread was sleeping and is now starting to run again.
                 // Accumulate the off-cpu time.
                 let off_duration = timestamp - off_switch_timestamp;
-                thread.off_cpu_duration_since_last_off_cpu_sample += off_duration;
+                *thread.off_cpu_duration_since_last_off_cpu_sample(reason) += off_duration;
 
                 // We just added some off-cpu time. If the accumulated off-cpu time exceeds the
                 // off-cpu sampling interval, we want to consume some of it and turn it into an
                 // off-cpu sampling group.
-                self.maybe_consume_off_cpu(timestamp, thread)
+                self.maybe_consume_off_cpu(timestamp, reason, thread)
             }
             ThreadState::Unknown => {
                 // This "switch-in" is the first time we've heard of the thread.
@@ -151,17 +166,18 @@ impl ContextSwitchHandler {
             }
             ThreadState::Off {
                 off_switch_timestamp,
+                reason,
             } => {
                 // The last time we heard from this thread, it was being context switched away from.
                 // We are processing a sample on it so we know it is running again. Treat this sample
                 // as a switch-in event.
                 let off_duration = timestamp - off_switch_timestamp;
-                thread.off_cpu_duration_since_last_off_cpu_sample += off_duration;
+                *thread.off_cpu_duration_since_last_off_cpu_sample(reason) += off_duration;
 
                 // We just added some off-cpu time. If the accumulated off-cpu time exceeds the
                 // off-cpu sampling interval, we want to consume some of it and turn it into an
                 // off-cpu sampling group.
-                self.maybe_consume_off_cpu(timestamp, thread)
+                self.maybe_consume_off_cpu(timestamp, reason, thread)
             }
             ThreadState::Unknown => {
                 // This sample is the first time we've ever head from a thread.
@@ -182,25 +198,25 @@ impl ContextSwitchHandler {
     fn maybe_consume_off_cpu(
         &self,
         timestamp: u64,
+        reason: OffCpuReason,
         thread: &mut ThreadContextSwitchData,
     ) -> Option<OffCpuSampleGroup> {
         // If the accumulated off-cpu time exceeds the off-cpu sampling interval,
         // we want to consume some of it and turn it into an off-cpu sampling group.
         let interval = self.off_cpu_sampling_interval_ns;
-        if thread.off_cpu_duration_since_last_off_cpu_sample < interval {
+        let accumulated_duration = thread.off_cpu_duration_since_last_off_cpu_sample(reason);
+        if *accumulated_duration < interval {
             return None;
         }
 
         // Let's turn the accumulated off-cpu time into an off-cpu sample group.
-        let sample_count = thread.off_cpu_duration_since_last_off_cpu_sample / interval;
+        let sample_count = *accumulated_duration / interval;
         debug_assert!(sample_count >= 1);
 
         let consumed_duration = sample_count * interval;
-        let remaining_duration =
-            thread.off_cpu_duration_since_last_off_cpu_sample - consumed_duration;
+        let remaining_duration = *accumulated_duration - consumed_duration;
 
-        let begin_timestamp =
-            timestamp - (thread.off_cpu_duration_since_last_off_cpu_sample - interval);
+        let begin_timestamp = timestamp - (*accumulated_duration - interval);
         let end_timestamp = timestamp - remaining_duration;
         debug_assert_eq!(
             end_timestamp - begin_timestamp,
@@ -208,12 +224,13 @@ impl ContextSwitchHandler {
         );
 
         // Consume the consumed duration and save the leftover duration.
-        thread.off_cpu_duration_since_last_off_cpu_sample = remaining_duration;
+        *accumulated_duration = remaining_duration;
 
         Some(OffCpuSampleGroup {
             begin_timestamp,
             end_timestamp,
             sample_count,
+            reason,
         })
     }
 
@@ -227,31 +244,51 @@ pub struct OffCpuSampleGroup {
     pub begin_timestamp: u64,
     pub end_timestamp: u64,
     pub sample_count: u64,
+    pub reason: OffCpuReason,
+}
+
+/// Why a thread was off-cpu, from the preempt flag of the switch-out record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OffCpuReason {
+    /// The thread was still runnable and was waiting for a CPU.
+    Preempted,
+    /// The thread was waiting for something else, for example a lock or I/O.
+    Blocked,
 }
 
 #[derive(Default, Clone, Debug, PartialEq, Eq)]
 pub struct ThreadContextSwitchData {
     state: ThreadState,
     on_cpu_duration_since_last_sample: u64,
-    off_cpu_duration_since_last_off_cpu_sample: u64,
+    preempted_duration_since_last_off_cpu_sample: u64,
+    blocked_duration_since_last_off_cpu_sample: u64,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-enum ThreadState {
-    Unknown,
-    Off { off_switch_timestamp: u64 },
-    On { last_observed_on_timestamp: u64 },
+impl ThreadContextSwitchData {
+    fn off_cpu_duration_since_last_off_cpu_sample(&mut self, reason: OffCpuReason) -> &mut u64 {
+        match reason {
+            OffCpuReason::Preempted => &mut self.preempted_duration_since_last_off_cpu_sample,
+            OffCpuReason::Blocked => &mut self.blocked_duration_since_last_off_cpu_sample,
+        }
+    }
 }
 
-impl Default for ThreadState {
-    fn default() -> Self {
-        ThreadState::Unknown
-    }
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+enum ThreadState {
+    #[default]
+    Unknown,
+    Off {
+        off_switch_timestamp: u64,
+        reason: OffCpuReason,
+    },
+    On {
+        last_observed_on_timestamp: u64,
+    },
 }
 
 #[cfg(test)]
 mod test {
-    use super::{ContextSwitchHandler, OffCpuSampleGroup, ThreadContextSwitchData};
+    use super::{ContextSwitchHandler, OffCpuReason, OffCpuSampleGroup, ThreadContextSwitchData};
 
     #[test]
     fn it_works() {
@@ -272,39 +309,41 @@ mod test {
         let handler = ContextSwitchHandler::new(10);
         let s = handler.handle_switch_in(0, &mut thread);
         assert_eq!(s, None);
-        handler.handle_switch_out(3, &mut thread);
+        handler.handle_switch_out(3, OffCpuReason::Blocked, &mut thread);
         let s = handler.handle_switch_in(5, &mut thread);
         assert_eq!(s, None);
         let s = handler.handle_sample(12, &mut thread);
         let delta = handler.consume_cpu_delta(&mut thread);
         assert_eq!(s, None);
         assert_eq!(delta, 10);
-        handler.handle_switch_out(13, &mut thread);
+        handler.handle_switch_out(13, OffCpuReason::Blocked, &mut thread);
         let s = handler.handle_switch_in(15, &mut thread);
         assert_eq!(s, None);
-        handler.handle_switch_out(16, &mut thread);
+        handler.handle_switch_out(16, OffCpuReason::Blocked, &mut thread);
         let s = handler.handle_switch_in(21, &mut thread);
         assert_eq!(s, None);
-        handler.handle_switch_out(23, &mut thread);
+        handler.handle_switch_out(23, OffCpuReason::Blocked, &mut thread);
         let s = handler.handle_switch_in(27, &mut thread);
         assert_eq!(
             s,
             Some(OffCpuSampleGroup {
                 begin_timestamp: 24,
                 end_timestamp: 24,
-                sample_count: 1
+                sample_count: 1,
+                reason: OffCpuReason::Blocked,
             })
         );
         let delta = handler.consume_cpu_delta(&mut thread);
         assert_eq!(delta, 4);
-        handler.handle_switch_out(30, &mut thread);
+        handler.handle_switch_out(30, OffCpuReason::Blocked, &mut thread);
         let s = handler.handle_switch_in(48, &mut thread);
         assert_eq!(
             s,
             Some(OffCpuSampleGroup {
                 begin_timestamp: 37,
                 end_timestamp: 47,
-                sample_count: 2
+                sample_count: 2,
+                reason: OffCpuReason::Blocked,
             })
         );
         let delta = handler.consume_cpu_delta(&mut thread);
@@ -318,4 +357,28 @@ mod test {
         assert_eq!(s, None);
         assert_eq!(delta, 10);
     }
+
+    #[test]
+    fn preempted_and_blocked_time_is_accumulated_separately() {
+        let mut thread = ThreadContextSwitchData::default();
+        let handler = ContextSwitchHandler::new(10);
+        handler.handle_switch_in(0, &mut thread);
+        handler.handle_switch_out(1, OffCpuReason::Preempted, &mut thread);
+        let s = handler.handle_switch_in(7, &mut thread);
+        assert_eq!(s, None);
+        handler.handle_switch_out(8, OffCpuReason::Blocked, &mut thread);
+        let s = handler.handle_switch_in(14, &mut thread);
+        assert_eq!(s, None);
+        handler.handle_switch_out(15, OffCpuReason::Preempted, &mut thread);
+        let s = handler.handle_sample(20, &mut thread);
+        assert_eq!(
+            s,
+            Some(OffCpuSampleGroup {
+                begin_timestamp: 19,
+                end_timestamp: 19,
+                sample_count: 1,
+                reason: OffCpuReason::Preempted,
+            })
+        );
+    }
 }
@@ -0,0 +1,39 @@
+use framehop::aarch64::UnwindRegsAarch64;
+use framehop::x86_64::UnwindRegsX86_64;
+use linux_perf_data::linux_perf_event_reader::constants::{
+    PERF_REG_ARM64_LR, PERF_REG_ARM64_PC, PERF_REG_ARM64_SP, PERF_REG_ARM64_X29, PERF_REG_X86_BP,
+    PERF_REG_X86_IP, PERF_REG_X86_SP,
+};
+use linux_perf_data::linux_perf_event_reader::Regs;
+
+pub trait ConvertRegs {
+    type UnwindRegs;
+    /// Returns the instruction pointer, the stack pointer, and the registers needed
+    /// for unwinding. Returns `None` if any of the required registers is missing.
+    fn convert_regs(regs: &Regs) -> Option<(u64, u64, Self::UnwindRegs)>;
+}
+
+pub struct ConvertRegsX86_64;
+impl ConvertRegs for ConvertRegsX86_64 {
+    type UnwindRegs = UnwindRegsX86_64;
+    fn convert_regs(regs: &Regs) -> Option<(u64, u64, UnwindRegsX86_64)> {
+        let ip = regs.get(PERF_REG_X86_IP)?;
+        let sp = regs.get(PERF_REG_X86_SP)?;
+        let bp = regs.get(PERF_REG_X86_BP)?;
+        let regs = UnwindRegsX86_64::new(ip, sp, bp);
+        Some((ip, sp, regs))
+    }
+}
+
+pub struct ConvertRegsAarch64;
+impl ConvertRegs for ConvertRegsAarch64 {
+    type UnwindRegs = UnwindRegsAarch64;
+    fn convert_regs(regs: &Regs) -> Option<(u64, u64, UnwindRegsAarch64)> {
+        let ip = regs.get(PERF_REG_ARM64_PC)?;
+        let lr = regs.get(PERF_REG_ARM64_LR)?;
+        let sp = regs.get(PERF_REG_ARM64_SP)?;
+        let fp = regs.get(PERF_REG_ARM64_X29)?;
+        let regs = UnwindRegsAarch64::new(lr, sp, fp);
+        Some((ip, sp, regs))
+    }
+}
@@ -0,0 +1,2249 @@
+use byteorder::LittleEndian;
+use debugid::{CodeId, DebugId};
+use framehop::{FrameAddress, Module, Unwinder};
+use fxprof_processed_profile::{
+    CategoryColor, CategoryHandle, CategoryPairHandle, CpuDelta, FrameInfo, LibraryInfo,
+    MarkerTiming, ProcessHandle, Profile, ReferenceTimestamp, SamplingInterval, StackHandle,
+    SymbolTable, ThreadHandle, Timestamp, WeightType,
+};
+use linux_perf_data::linux_perf_event_reader;
+use linux_perf_data::{DsoInfo, DsoKey, PerfFile, PerfFileReader, PerfFileRecord};
+use linux_perf_event_reader::constants::PERF_CONTEXT_MAX;
+use linux_perf_event_reader::{
+    CommOrExecRecord, CommonData, ContextSwitchRecord, EventRecord, ForkOrExitRecord, Mmap2FileId,
+    Mmap2Record, MmapRecord, RawDataU64, SampleRecord, TaskWasPreempted,
+};
+use profiler_get_symbols::DebugIdExt;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::Read;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::binary_lookup::{BinaryLookup, ProcessRoot};
+use crate::clock::{recording_start_time, ClockData};
+use crate::context_switch::{
+    ContextSwitchHandler, OffCpuReason, OffCpuSampleGroup, ThreadContextSwitchData,
+};
+use crate::convert_regs::ConvertRegs;
+use crate::cpus::Cpus;
+use crate::error::ConvertError;
+use crate::event_interpretation::{EventInterpretation, OtherSampledEvent, TracepointPairSide};
+use crate::jitdump::{code_blobs_and_symbols, is_jitdump_path, parse_jitdump, ProcessJitCode};
+use crate::markers::{
+    MarkerFieldValue, RunqueueLatencyMarker, TracepointMarkerType, TracepointMarkerTypeHandles,
+    WakeupMarker, WakingMarker,
+};
+use crate::options::{ConversionOptions, TimeRangeBound, Verbosity};
+use crate::perf_map::{find_perf_map_file, parse_perf_map, PerfMapLib};
+use crate::runqueue_latency::RunqueueLatencies;
+use crate::stack::{OffCpuRootFrames, ProcessLibMappings, StackConverter, StackFrame, StackMode};
+use crate::symbolication::ProcessSymbols;
+use crate::task_filter::TaskFilter;
+use crate::tracing_data::{FieldFormat, FieldValue, TracingData};
+use crate::unwinding::{add_module_to_unwinder, BinaryCache};
+use crate::warnings::{Warning, Warnings};
+
+/// What needs to be known about a perf.data file before the profile can be
+/// created: its main event, and when its samples were taken.
+pub struct RecordingInfo {
+    pub interpretation: EventInterpretation,
+    /// The perf timestamps of the records to convert.
+    time_range: Range<u64>,
+    /// The perf timestamp at which this file's part of the profile starts.
+    first_sample_time: u64,
+    /// The wall-clock time which corresponds to `first_sample_time`.
+    pub start_time: SystemTime,
+}
+
+impl RecordingInfo {
+    pub fn new(
+        perf_file: &PerfFile,
+        options: &ConversionOptions,
+        file_mtime: Option<SystemTime>,
+    ) -> Result<Self, ConvertError> {
+        let sample_time_range = perf_file.sample_time_range()?;
+        let time_range = time_range(
+            options.time_range_start,
+            options.time_range_end,
+            sample_time_range.as_ref().map(|r| r.first_sample_time),
+        )?;
+        let (first_sample_time, last_sample_time) =
+            sample_time_range.map_or((0, 0), |r| (r.first_sample_time, r.last_sample_time));
+        // The profile starts at the start of the time range.
+        let first_sample_time = first_sample_time.max(time_range.start);
+        let clock_data = ClockData::from_perf_file(perf_file);
+        if let (Some(clock_data), Verbosity::Verbose) = (&clock_data, options.verbosity) {
+            eprintln!(
+                "Sample timestamps use clock {}; {}ns on that clock corresponds to {}ns since the Unix epoch",
+                clock_data.clockid, clock_data.clockid_time_ns, clock_data.wall_clock_ns
+            );
+        }
+        let start_time = recording_start_time(
+            clock_data.as_ref(),
+            file_mtime,
+            first_sample_time,
+            last_sample_time,
+        )
+        .unwrap_or_else(SystemTime::now);
+        let interpretation = EventInterpretation::divine_from_attrs(
+            perf_file.event_attributes(),
+            options.main_event.as_deref(),
+        )?;
+        if options.verbosity >= Verbosity::Verbose {
+            eprintln!("Using {} as the main event", interpretation.main_event_name);
+        }
+        Ok(Self {
+            interpretation,
+            time_range,
+            first_sample_time,
+            start_time,
+        })
+    }
+}
+
+/// The perf timestamps between the given bounds. `first_sample_time` is
+/// `None` if the file doesn't record it, i.e. if it has no HEADER_SAMPLE_TIME.
+fn time_range(
+    start: Option<TimeRangeBound>,
+    end: Option<TimeRangeBound>,
+    first_sample_time: Option<u64>,
+) -> Result<Range<u64>, ConvertError> {
+    let resolve = |bound: TimeRangeBound| match (bound, first_sample_time) {
+        (TimeRangeBound::Absolute(_), _) => Ok(bound.resolve(0)),
+        (TimeRangeBound::SinceFirstSample(_), Some(first_sample_time)) => {
+            Ok(bound.resolve(first_sample_time))
+        }
+        (TimeRangeBound::SinceFirstSample(_), None) => Err(ConvertError::UnknownFirstSampleTime),
+    };
+    let time_range = start.map_or(Ok(0), resolve)?..end.map_or(Ok(u64::MAX), resolve)?;
+    if time_range.is_empty() {
+        return Err(ConvertError::EmptyTimeRange {
+            start: time_range.start,
+            end: time_range.end,
+        });
+    }
+    Ok(time_range)
+}
+
+/// The profile which is being built, with the categories and frames which
+/// the converters of all input files share.
+pub struct SharedProfile {
+    profile: Profile,
+    user_category: CategoryPairHandle,
+    kernel_category: CategoryPairHandle,
+    tracepoint_category: CategoryHandle,
+    scheduling_category: CategoryHandle,
+    off_cpu_root_frames: OffCpuRootFrames,
+    tracepoint_marker_type_handles: TracepointMarkerTypeHandles,
+    have_product_name: bool,
+}
+
+impl SharedProfile {
+    /// Create the profile. Its sampling interval is the one of the main event
+    /// in `interpretation`, and it starts at `reference_time`.
+    pub fn new(
+        reference_time: SystemTime,
+        interpretation: &EventInterpretation,
+        options: &ConversionOptions,
+    ) -> Self {
+        let interval = match interpretation.sampling_is_time_based {
+            Some(nanos) => SamplingInterval::from_nanos(nanos),
+            None => SamplingInterval::from_millis(1),
+        };
+        let mut profile = Profile::new(
+            "Converted perf profile",
+            ReferenceTimestamp::from_system_time(reference_time),
+            interval,
+        );
+        if options.symbolicate {
+            // Inlined functions are already in the stacks as label frames, so
+            // the profiler must not add them again.
+            profile.set_symbolicated(true);
+        }
+        let user_category = profile.add_category("User", CategoryColor::Yellow).into();
+        let kernel_category = profile.add_category("Kernel", CategoryColor::Orange).into();
+        let tracepoint_category = profile.add_category("Tracepoint", CategoryColor::Green);
+        let scheduling_category = profile.add_category("Scheduling", CategoryColor::Blue);
+        let off_cpu_root_frames = OffCpuRootFrames::new(&mut profile);
+        Self {
+            profile,
+            user_category,
+            kernel_category,
+            tracepoint_category,
+            scheduling_category,
+            off_cpu_root_frames,
+            tracepoint_marker_type_handles: TracepointMarkerTypeHandles::default(),
+            have_product_name: false,
+        }
+    }
+
+    pub fn into_profile(self) -> Profile {
+        self.profile
+    }
+}
+
+/// Convert the records of `file` and add them to `shared`.
+///
+/// The file's first sample is put `time_offset_ns` after the start of the
+/// profile. If the profile is made from multiple files, `input_name` is the
+/// name of this file, and its process names get the host and file name
+/// appended, so that they can be told apart from the processes of the other
+/// files.
+pub fn convert<U, C, R>(
+    file: PerfFileReader<R>,
+    info: RecordingInfo,
+    options: &ConversionOptions,
+    cache: U::Cache,
+    shared: SharedProfile,
+    time_offset_ns: u64,
+    input_name: Option<&str>,
+) -> Result<SharedProfile, ConvertError>
+where
+    U: Unwinder<Module = Module<Vec<u8>>> + Default,
+    C: ConvertRegs<UnwindRegs = U::UnwindRegs>,
+    R: Read,
+{
+    let PerfFileReader {
+        mut perf_file,
+        mut record_iter,
+    } = file;
+    let RecordingInfo {
+        interpretation,
+        time_range,
+        first_sample_time,
+        ..
+    } = info;
+    let build_ids = perf_file.build_ids().ok().unwrap_or_default();
+    let little_endian = perf_file.endian() == linux_perf_data::Endianness::LittleEndian;
+    // The following header fields are only informational, so don't fail if they're malformed.
+    let host = perf_file
+        .hostname()
+        .ok()
+        .flatten()
+        .unwrap_or("<unknown host>");
+    let perf_version = perf_file
+        .perf_version()
+        .ok()
+        .flatten()
+        .unwrap_or("<unknown version>");
+    let linux_version = perf_file.os_release().ok().flatten();
+    let process_label = input_name.map(|name| format!("{}/{}", host, name));
+    let tracing_data = TracingData::from_perf_file(&perf_file).unwrap_or_default();
+    let task_filter = TaskFilter::new(
+        &options.pid_filter,
+        &options.tid_filter,
+        options.comm_filter.as_deref(),
+    )
+    .map_err(ConvertError::InvalidCommFilter)?;
+
+    let mut converter = Converter::<U>::new(
+        shared,
+        build_ids,
+        TimestampConverter {
+            reference_ns: first_sample_time,
+            offset_ns: time_offset_ns,
+        },
+        host,
+        perf_version,
+        linux_version,
+        little_endian,
+        cache,
+        options,
+        interpretation.clone(),
+        tracing_data,
+        task_filter,
+        process_label,
+    );
+
+    let mut last_timestamp = 0;
+
+    loop {
+        let record = match record_iter.next_record(&mut perf_file) {
+            Ok(Some(record)) => record,
+            Ok(None) => break,
+            Err(err) => {
+                // Keep what we have so far instead of throwing away the entire conversion.
+                if options.verbosity >= Verbosity::Verbose {
+                    eprintln!("Error while reading records: {}", err);
+                }
+                converter.warnings.add(Warning::TruncatedRecordData);
+                break;
+            }
+        };
+        let (record, parsed_record, attr_index) = match record {
+            PerfFileRecord::EventRecord { attr_index, record } => match record.parse() {
+                Ok(r) => (record, r, attr_index),
+                Err(_) => {
+                    converter.warnings.add(Warning::UnparsableRecord);
+                    continue;
+                }
+            },
+            PerfFileRecord::UserRecord(_) => continue,
+        };
+        if let Some(timestamp) = record.timestamp() {
+            if timestamp < last_timestamp && options.verbosity >= Verbosity::Verbose {
+                eprintln!(
+                    "bad timestamp ordering; {} is earlier but arrived after {}",
+                    timestamp, last_timestamp
+                );
+            }
+            last_timestamp = timestamp;
+        }
+        let outside_time_range = matches!(
+            record.timestamp(),
+            Some(timestamp) if !time_range.contains(&timestamp)
+        );
+        if outside_time_range
+            && matches!(
+                parsed_record,
+                EventRecord::Sample(_) | EventRecord::ContextSwitch(_)
+            )
+        {
+            continue;
+        }
+        match parsed_record {
+            EventRecord::Sample(e) => {
+                if attr_index == interpretation.main_event_attr_index {
+                    converter.handle_sample::<C>(e);
+                } else if interpretation.sched_switch_attr_index == Some(attr_index) {
+                    converter.handle_sched_switch::<C>(e);
+                } else if interpretation.is_sched_wakeup(attr_index) {
+                    converter.handle_sched_wakeup::<C>(e);
+                } else if let Some(event) = interpretation.other_sampled_event(attr_index) {
+                    converter.handle_other_event_sample::<C>(e, event);
+                } else if interpretation.is_tracepoint_event(attr_index) {
+                    converter.handle_tracepoint_sample::<C>(e, attr_index);
+                } else if let Some((pair_index, side)) = interpretation.tracepoint_pair(attr_index)
+                {
+                    converter.handle_tracepoint_pair_sample::<C>(e, pair_index, side);
+                }
+            }
+            EventRecord::Fork(e) => {
+                converter.handle_thread_start(e);
+            }
+            EventRecord::Comm(e) => {
+                converter.handle_thread_name_update(e, record.timestamp());
+            }
+            EventRecord::Exit(e) => {
+                converter.handle_thread_end(e);
+            }
+            EventRecord::Mmap(e) => {
+                converter.handle_mmap(e);
+            }
+            EventRecord::Mmap2(e) => {
+                converter.handle_mmap2(e);
+            }
+            EventRecord::ContextSwitch(e) => {
+                let common = match record.common_data() {
+                    Ok(common) => common,
+                    Err(_) => {
+                        converter.warnings.add(Warning::UnparsableRecord);
+                        continue;
+                    }
+                };
+                converter.handle_context_switch(e, common);
+            }
+            _ => {
+                // println!("{:?}", record.record_type);
+            }
+        }
+    }
+
+    if options.verbosity >= Verbosity::Normal {
+        if let Some(runqueue_latencies) = &converter.runqueue_latencies {
+            runqueue_latencies.report();
+        }
+    }
+    let (shared, warnings) = converter.finish();
+    if options.verbosity >= Verbosity::Normal {
+        warnings.report();
+    }
+    Ok(shared)
+}
+
+struct Converter<U>
+where
+    U: Unwinder<Module = Module<Vec<u8>>> + Default,
+{
+    cache: U::Cache,
+    profile: Profile,
+    processes: Processes<U>,
+    threads: Threads,
+    stack_converter: StackConverter,
+    timestamp_converter: TimestampConverter,
+    current_sample_time: u64,
+    build_ids: HashMap<DsoKey, DsoInfo>,
+    little_endian: bool,
+    have_product_name: bool,
+    host: String,
+    perf_version: String,
+    linux_version: Option<String>,
+    binary_lookup: BinaryLookup,
+    binary_cache: BinaryCache,
+    verbosity: Verbosity,
+    symbolicate: bool,
+    context_switch_handler: ContextSwitchHandler,
+    sample_weights: SampleWeights,
+    off_cpu_root_frames: OffCpuRootFrames,
+    have_context_switches: bool,
+    interpretation: EventInterpretation,
+    /// The marker types for tracepoint events, keyed by attribute index.
+    tracepoint_marker_types: HashMap<usize, TracepointMarkerType>,
+    /// The marker types for pairs of entry and exit tracepoints, in the same
+    /// order as `interpretation.tracepoint_pairs`.
+    tracepoint_pair_marker_types: Vec<TracepointMarkerType>,
+    /// Passed on to the converters of the following files, if multiple files
+    /// are merged.
+    tracepoint_marker_type_handles: TracepointMarkerTypeHandles,
+    tracepoint_category: CategoryHandle,
+    scheduling_category: CategoryHandle,
+    sched_wakeup_tid_field: Option<FieldFormat>,
+    /// The most recent wakeup of each thread, keyed by the tid of the woken
+    /// thread, until the thread's off-CPU samples are emitted.
+    pending_wakeups: HashMap<i32, PendingWakeup>,
+    /// The mappings of processes which none of the threads matching the task
+    /// filter belong to yet, keyed by pid.
+    pending_mappings: HashMap<i32, Vec<ProcessMapping>>,
+    /// The runqueue latency measurement, if the recording has both wakeups
+    /// and context switches.
+    runqueue_latencies: Option<RunqueueLatencies>,
+    /// The per-CPU tracks, if requested.
+    cpus: Option<Cpus>,
+    task_filter: TaskFilter,
+    tracing_data_is_big_endian: bool,
+    warnings: Warnings,
+}
+
+const DEFAULT_OFF_CPU_SAMPLING_INTERVAL_NS: u64 = 1_000_000; // 1ms
+const DEFAULT_RUNQUEUE_LATENCY_THRESHOLD_NS: u64 = 1_000_000; // 1ms
+
+impl<U> Converter<U>
+where
+    U: Unwinder<Module = Module<Vec<u8>>> + Default,
+{
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        shared: SharedProfile,
+        build_ids: HashMap<DsoKey, DsoInfo>,
+        timestamp_converter: TimestampConverter,
+        host: &str,
+        perf_version: &str,
+        linux_version: Option<&str>,
+        little_endian: bool,
+        cache: U::Cache,
+        options: &ConversionOptions,
+        interpretation: EventInterpretation,
+        tracing_data: TracingData,
+        task_filter: TaskFilter,
+        process_label: Option<String>,
+    ) -> Self {
+        let SharedProfile {
+            mut profile,
+            user_category,
+            kernel_category,
+            tracepoint_category,
+            scheduling_category,
+            off_cpu_root_frames,
+            mut tracepoint_marker_type_handles,
+            have_product_name,
+        } = shared;
+        let cpus = options.cpu_tracks.then(|| {
+            let name = process_display_name("CPUs", process_label.as_deref());
+            Cpus::new(&mut profile, &name, scheduling_category)
+        });
+        // The "pid" field of sched_waking and sched_wakeup is the ID of the
+        // thread which is woken up.
+        let sched_wakeup_tid_field = interpretation
+            .sched_wakeup
+            .as_ref()
+            .and_then(|event| tracing_data.event_formats.get(&event.tracepoint_id))
+            .and_then(|format| format.fields.iter().find(|field| field.name == "pid"))
+            .cloned();
+        let runqueue_latencies = (interpretation.sched_wakeup.is_some()
+            && interpretation.have_context_switches)
+            .then(|| {
+                RunqueueLatencies::new(
+                    options
+                        .runqueue_latency_threshold_ns
+                        .unwrap_or(DEFAULT_RUNQUEUE_LATENCY_THRESHOLD_NS),
+                )
+            });
+        let tracepoint_marker_types = interpretation
+            .tracepoint_events
+            .iter()
+            .map(|event| {
+                let format = tracing_data.event_formats.get(&event.tracepoint_id);
+                let marker_type = TracepointMarkerType::register(
+                    &mut profile,
+                    &mut tracepoint_marker_type_handles,
+                    &event.name,
+                    &[format],
+                );
+                (event.attr_index, marker_type)
+            })
+            .collect();
+        let tracepoint_pair_marker_types = interpretation
+            .tracepoint_pairs
+            .iter()
+            .map(|pair| {
+                let entry_format = tracing_data.event_formats.get(&pair.entry.tracepoint_id);
+                let exit_format = tracing_data.event_formats.get(&pair.exit.tracepoint_id);
+                TracepointMarkerType::register(
+                    &mut profile,
+                    &mut tracepoint_marker_type_handles,
+                    &pair.name,
+                    &[entry_format, exit_format],
+                )
+            })
+            .collect();
+        let (off_cpu_sampling_interval_ns, off_cpu_weight_per_sample) = match (
+            options.off_cpu_sampling_interval_ns,
+            interpretation.sampling_is_time_based,
+        ) {
+            (Some(off_cpu_interval_ns), Some(interval_ns)) => {
+                // Weight each off-CPU sample so that it's comparable to the on-CPU samples.
+                let weight = (off_cpu_interval_ns / interval_ns).max(1);
+                (
+                    off_cpu_interval_ns,
+                    i32::try_from(weight).unwrap_or(i32::MAX),
+                )
+            }
+            (Some(off_cpu_interval_ns), None) => (off_cpu_interval_ns, 0),
+            (None, Some(interval_ns)) => (interval_ns, 1),
+            (None, None) => (DEFAULT_OFF_CPU_SAMPLING_INTERVAL_NS, 0),
+        };
+        let sample_weights = if options.weight_samples_by_time {
+            SampleWeights::Time {
+                off_cpu_sampling_interval_ns,
+            }
+        } else {
+            SampleWeights::Count {
+                off_cpu_weight_per_sample,
+            }
+        };
+        let binary_lookup = BinaryLookup::new(options);
+        Self {
+            profile,
+            cache,
+            processes: Processes {
+                processes: HashMap::new(),
+                perf_map_dirs: std::iter::once(binary_lookup.path_in_sysroot(Path::new("/tmp")))
+                    .chain(options.extra_dir.clone())
+                    .collect(),
+                label: process_label,
+            },
+            threads: Threads {
+                threads: HashMap::new(),
+                samples_weight_type: match sample_weights {
+                    SampleWeights::Count { .. } => WeightType::Samples,
+                    SampleWeights::Time { .. } => WeightType::TracingMs,
+                },
+            },
+            stack_converter: StackConverter {
+                user_category,
+                kernel_category,
+            },
+            current_sample_time: timestamp_converter.reference_ns,
+            timestamp_converter,
+            build_ids,
+            little_endian,
+            have_product_name,
+            host: host.to_string(),
+            perf_version: perf_version.to_string(),
+            linux_version: linux_version.map(ToOwned::to_owned),
+            binary_lookup,
+            binary_cache: BinaryCache::default(),
+            verbosity: options.verbosity,
+            symbolicate: options.symbolicate,
+            sample_weights,
+            context_switch_handler: ContextSwitchHandler::new(off_cpu_sampling_interval_ns),
+            off_cpu_root_frames,
+            have_context_switches: interpretation.have_context_switches,
+            interpretation,
+            tracepoint_marker_types,
+            tracepoint_pair_marker_types,
+            tracepoint_marker_type_handles,
+            tracepoint_category,
+            scheduling_category,
+            sched_wakeup_tid_field,
+            pending_wakeups: HashMap::new(),
+            pending_mappings: HashMap::new(),
+            runqueue_latencies,
+            cpus,
+            task_filter,
+            tracing_data_is_big_endian: tracing_data.big_endian,
+            warnings: Warnings::default(),
+        }
+    }
+
+    pub fn finish(mut self) -> (SharedProfile, Warnings) {
+        if let Some(cpus) = &mut self.cpus {
+            cpus.finish(&mut self.profile);
+        }
+        for thread in self.threads.threads.values_mut() {
+            Self::end_open_tracepoint_intervals(
+                thread,
+                &mut self.profile,
+                &self.tracepoint_pair_marker_types,
+                self.tracepoint_category,
+            );
+        }
+        let shared = SharedProfile {
+            profile: self.profile,
+            user_category: self.stack_converter.user_category,
+            kernel_category: self.stack_converter.kernel_category,
+            tracepoint_category: self.tracepoint_category,
+            scheduling_category: self.scheduling_category,
+            off_cpu_root_frames: self.off_cpu_root_frames,
+            tracepoint_marker_type_handles: self.tracepoint_marker_type_handles,
+            have_product_name: self.have_product_name,
+        };
+        (shared, self.warnings)
+    }
+
+    pub fn handle_sample<C: ConvertRegs<UnwindRegs = U::UnwindRegs>>(&mut self, e: SampleRecord) {
+        let (pid, tid, timestamp) = match self.pid_tid_timestamp(e.pid, e.tid, e.timestamp) {
+            Some(ids) => ids,
+            None => return,
+        };
+        if !self.thread_matches(pid, tid) {
+            return;
+        }
+        self.current_sample_time = timestamp;
+
+        let profile_timestamp = self.timestamp_converter.convert_time(timestamp);
+
+        let is_main = pid == tid;
+        let stack = match self.unwind_sample::<C>(&e, pid, timestamp) {
+            Some(stack) => stack,
+            None => return,
+        };
+        let process = self.processes.get_by_pid(pid, &mut self.profile);
+
+        let thread =
+            self.threads
+                .get_by_tid(tid, process.profile_process, is_main, &mut self.profile);
+
+        if thread.last_sample_timestamp == Some(timestamp) {
+            // Duplicate sample. Ignore.
+            return;
+        }
+
+        let thread_handle = thread.profile_thread;
+
+        let off_cpu_sample = self
+            .context_switch_handler
+            .handle_sample(timestamp, &mut thread.context_switch_data);
+        if let Some(off_cpu_sample) = off_cpu_sample {
+            let cpu_delta_ns = self
+                .context_switch_handler
+                .consume_cpu_delta(&mut thread.context_switch_data);
+            let off_cpu_stack = self
+                .off_cpu_root_frames
+                .stack(off_cpu_sample.reason, &thread.off_cpu_stack);
+            process_off_cpu_sample_group(
+                off_cpu_sample,
+                thread_handle,
+                cpu_delta_ns,
+                &self.timestamp_converter,
+                self.sample_weights,
+                &mut thread.time_weights,
+                off_cpu_stack,
+                &mut self.profile,
+            );
+            if let Some(wakeup) = self.pending_wakeups.remove(&tid) {
+                wakeup.add_marker(thread_handle, &mut self.profile);
+            }
+        }
+        // Clear any saved off-CPU stack.
+        thread.off_cpu_stack = Vec::new();
+
+        let cpu_delta_ns = if self.have_context_switches {
+            self.context_switch_handler
+                .consume_cpu_delta(&mut thread.context_switch_data)
+        } else {
+            // If the observed perf event is one of the clock time events, or cycles, then we should convert it to a CpuDelta.
+            // TODO: Detect event type
+            e.period.unwrap_or(0)
+        };
+        let cpu_delta = CpuDelta::from_nanos(cpu_delta_ns);
+        let weight = self
+            .sample_weights
+            .on_cpu_weight(cpu_delta_ns, &mut thread.time_weights);
+
+        let frames = self.stack_converter.convert_stack(stack);
+        let frames = process.symbols.add_inline_frames(frames, &mut self.profile);
+        if let (Some(cpus), Some(cpu)) = (&mut self.cpus, e.cpu) {
+            let cpu_thread = cpus.thread_for_cpu(cpu, &mut self.profile);
+            let cpu_frames = frames
+                .iter()
+                .map(|frame| process.lib_mappings.resolve_frame(frame.clone()));
+            let cpu_stack = self.profile.intern_stack_frames(cpu_thread, cpu_frames);
+            self.profile
+                .add_sample(cpu_thread, profile_timestamp, cpu_stack, cpu_delta, 1);
+        }
+        let stack = self
+            .profile
+            .intern_stack_frames(thread_handle, frames.into_iter());
+        self.profile
+            .add_sample(thread_handle, profile_timestamp, stack, cpu_delta, weight);
+        thread.last_sample_timestamp = Some(timestamp);
+    }
+
+    /// Add a sample of a sampled event other than the main event. These samples
+    /// go on a separate thread track, so that the call tree of each event can
+    /// be looked at on its own.
+    pub fn handle_other_event_sample<C: ConvertRegs<UnwindRegs = U::UnwindRegs>>(
+        &mut self,
+        e: SampleRecord,
+        event: &OtherSampledEvent,
+    ) {
+        let (pid, tid, timestamp) = match self.pid_tid_timestamp(e.pid, e.tid, e.timestamp) {
+            Some(ids) => ids,
+            None => return,
+        };
+        if !self.thread_matches(pid, tid) {
+            return;
+        }
+        let profile_timestamp = self.timestamp_converter.convert_time(timestamp);
+
+        let is_main = pid == tid;
+        let stack = match self.unwind_sample::<C>(&e, pid, timestamp) {
+            Some(stack) => stack,
+            None => return,
+        };
+        let process = self.processes.get_by_pid(pid, &mut self.profile);
+
+        let process_handle = process.profile_process;
+        let thread = self
+            .threads
+            .get_by_tid(tid, process_handle, is_main, &mut self.profile);
+        let thread_name = thread.name.as_deref();
+        let profile = &mut self.profile;
+        let thread_handle = *thread
+            .other_event_threads
+            .entry(event.attr_index)
+            .or_insert_with(|| {
+                let handle = profile.add_thread(
+                    process_handle,
+                    tid as u32,
+                    Timestamp::from_millis_since_reference(0.0),
+                    false,
+                );
+                profile.set_thread_name(handle, &other_event_thread_name(thread_name, &event.name));
+                // The weights are event counts, or sample counts for clock
+                // events, even if the main threads are weighted by time.
+                profile.set_thread_samples_weight_type(handle, WeightType::Samples);
+                handle
+            });
+
+        let weight = match (event.weight_by_period, e.period) {
+            (true, Some(period)) => i32::try_from(period).unwrap_or(i32::MAX),
+            _ => 1,
+        };
+        let frames = self.stack_converter.convert_stack(stack);
+        let frames = process.symbols.add_inline_frames(frames, &mut self.profile);
+        let stack = self
+            .profile
+            .intern_stack_frames(thread_handle, frames.into_iter());
+        self.profile.add_sample(
+            thread_handle,
+            profile_timestamp,
+            stack,
+            CpuDelta::ZERO,
+            weight,
+        );
+    }
+
+    /// Add a marker for a tracepoint sample, with the tracepoint's fields and
+    /// with the sample's stack as the marker stack.
+    pub fn handle_tracepoint_sample<C: ConvertRegs<UnwindRegs = U::UnwindRegs>>(
+        &mut self,
+        e: SampleRecord,
+        attr_index: usize,
+    ) {
+        let (pid, tid, timestamp) = match self.pid_tid_timestamp(e.pid, e.tid, e.timestamp) {
+            Some(ids) => ids,
+            None => return,
+        };
+        if !self.thread_matches(pid, tid) {
+            return;
+        }
+        if !self.tracepoint_marker_types.contains_key(&attr_index) {
+            return;
+        }
+        let profile_timestamp = self.timestamp_converter.convert_time(timestamp);
+
+        let is_main = pid == tid;
+        let stack = match self.unwind_sample::<C>(&e, pid, timestamp) {
+            Some(stack) => stack,
+            None => return,
+        };
+        let process = self.processes.get_by_pid(pid, &mut self.profile);
+        let marker_type = &self.tracepoint_marker_types[&attr_index];
+
+        let thread =
+            self.threads
+                .get_by_tid(tid, process.profile_process, is_main, &mut self.profile);
+        let thread_handle = thread.profile_thread;
+
+        let raw = e.raw.map(|raw| raw.as_slice());
+        let field_values = marker_type.decode_fields(
+            0,
+            raw.as_deref(),
+            self.tracing_data_is_big_endian,
+            &mut self.profile,
+        );
+        let marker = marker_type.make_marker(field_values, self.tracepoint_category);
+        let marker_handle = self.profile.add_marker(
+            thread_handle,
+            MarkerTiming::Instant(profile_timestamp),
+            marker,
+        );
+        let frames = self.stack_converter.convert_stack(stack);
+        let frames = process.symbols.add_inline_frames(frames, &mut self.profile);
+        let stack = self
+            .profile
+            .intern_stack_frames(thread_handle, frames.into_iter());
+        self.profile
+            .set_marker_stack(thread_handle, marker_handle, stack);
+    }
+
+    /// Handle a sample of an entry or exit tracepoint. Entries are remembered
+    /// per thread, and each exit is matched with the thread's most recent
+    /// unmatched entry of the same pair, which gives an interval marker with
+    /// the fields of both samples and with the entry's stack.
+    ///
+    /// Exits without an entry, e.g. for syscalls which were already running
+    /// when the recording started, become markers which end at the exit.
+    pub fn handle_tracepoint_pair_sample<C: ConvertRegs<UnwindRegs = U::UnwindRegs>>(
+        &mut self,
+        e: SampleRecord,
+        pair_index: usize,
+        side: TracepointPairSide,
+    ) {
+        let (pid, tid, timestamp) = match self.pid_tid_timestamp(e.pid, e.tid, e.timestamp) {
+            Some(ids) => ids,
+            None => return,
+        };
+        if !self.thread_matches(pid, tid) {
+            return;
+        }
+        let profile_timestamp = self.timestamp_converter.convert_time(timestamp);
+
+        let is_main = pid == tid;
+        let stack = match self.unwind_sample::<C>(&e, pid, timestamp) {
+            Some(stack) => stack,
+            None => return,
+        };
+        let process = self.processes.get_by_pid(pid, &mut self.profile);
+        let marker_type = &self.tracepoint_pair_marker_types[pair_index];
+
+        let thread =
+            self.threads
+                .get_by_tid(tid, process.profile_process, is_main, &mut self.profile);
+        let thread_handle = thread.profile_thread;
+        let frames = self.stack_converter.convert_stack(stack);
+        let frames = process.symbols.add_inline_frames(frames, &mut self.profile);
+        let stack = self
+            .profile
+            .intern_stack_frames(thread_handle, frames.into_iter());
+
+        let raw = e.raw.map(|raw| raw.as_slice());
+        let format_index = match side {
+            TracepointPairSide::Entry => 0,
+            TracepointPairSide::Exit => 1,
+        };
+        let field_values = marker_type.decode_fields(
+            format_index,
+            raw.as_deref(),
+            self.tracing_data_is_big_endian,
+            &mut self.profile,
+        );
+        let open_intervals = thread
+            .open_tracepoint_intervals
+            .entry(pair_index)
+            .or_default();
+        if side == TracepointPairSide::Entry {
+            open_intervals.push(OpenTracepointInterval {
+                start: profile_timestamp,
+                field_values,
+                stack,
+            });
+            return;
+        }
+
+        let (timing, mut all_field_values, stack) = match open_intervals.pop() {
+            Some(entry) => (
+                MarkerTiming::Interval(entry.start, profile_timestamp),
+                entry.field_values,
+                entry.stack,
+            ),
+            None => {
+                let entry_field_values = marker_type.decode_fields(
+                    0,
+                    None,
+                    self.tracing_data_is_big_endian,
+                    &mut self.profile,
+                );
+                (
+                    MarkerTiming::IntervalEnd(profile_timestamp),
+                    entry_field_values,
+                    stack,
+                )
+            }
+        };
+        all_field_values.extend(field_values);
+        let marker = marker_type.make_marker(all_field_values, self.tracepoint_category);
+        let marker_handle = self.profile.add_marker(thread_handle, timing, marker);
+        self.profile
+            .set_marker_stack(thread_handle, marker_handle, stack);
+    }
+
+    /// Add markers for the entries of tracepoint pairs which haven't seen
+    /// their exit, e.g. because the thread ended or the recording stopped.
+    fn end_open_tracepoint_intervals(
+        thread: &mut Thread,
+        profile: &mut Profile,
+        marker_types: &[TracepointMarkerType],
+        category: CategoryHandle,
+    ) {
+        for (pair_index, open_intervals) in thread.open_tracepoint_intervals.drain() {
+            let marker_type = &marker_types[pair_index];
+            for entry in open_intervals {
+                let mut field_values = entry.field_values;
+                field_values.extend(marker_type.decode_fields(1, None, false, profile));
+                let marker = marker_type.make_marker(field_values, category);
+                let marker_handle = profile.add_marker(
+                    thread.profile_thread,
+                    MarkerTiming::IntervalStart(entry.start),
+                    marker,
+                );
+                profile.set_marker_stack(thread.profile_thread, marker_handle, entry.stack);
+            }
+        }
+    }
+
+    pub fn handle_sched_switch<C: ConvertRegs<UnwindRegs = U::UnwindRegs>>(
+        &mut self,
+        e: SampleRecord,
+    ) {
+        let (pid, tid, timestamp) = match self.pid_tid_timestamp(e.pid, e.tid, e.timestamp) {
+            Some(ids) => ids,
+            None => return,
+        };
+        if !self.thread_matches(pid, tid) {
+            return;
+        }
+        let is_main = pid == tid;
+        let stack = match self.unwind_sample::<C>(&e, pid, timestamp) {
+            Some(stack) => stack,
+            None => return,
+        };
+        let process = self.processes.get_by_pid(pid, &mut self.profile);
+
+        let frames = self.stack_converter.convert_stack_no_kernel(&stack);
+        let stack = process.symbols.add_inline_frames(frames, &mut self.profile);
+
+        let thread =
+            self.threads
+                .get_by_tid(tid, process.profile_process, is_main, &mut self.profile);
+        thread.off_cpu_stack = stack;
+    }
+
+    /// Handle a sample of `sched:sched_waking` or `sched:sched_wakeup`. The
+    /// sample is taken on the waking thread, so its stack shows what woke up
+    /// the other thread, e.g. the release of a lock which it was waiting for.
+    ///
+    /// The wakeup is remembered for the woken thread, even if that thread
+    /// hasn't been seen yet, and becomes a marker when the thread's next
+    /// off-CPU samples are emitted. The marker only gets the waker's stack if
+    /// both threads are in the same process, because the stack's addresses
+    /// are looked up in the process of the marker's thread. Otherwise the
+    /// waker's stack goes on a marker on the waker's own thread.
+    pub fn handle_sched_wakeup<C: ConvertRegs<UnwindRegs = U::UnwindRegs>>(
+        &mut self,
+        e: SampleRecord,
+    ) {
+        let (pid, tid, timestamp) = match self.pid_tid_timestamp(e.pid, e.tid, e.timestamp) {
+            Some(ids) => ids,
+            None => return,
+        };
+        let raw = e.raw.map(|raw| raw.as_slice());
+        let wakee_tid = match (&self.sched_wakeup_tid_field, raw.as_deref()) {
+            (Some(field), Some(raw)) => match field.decode(raw, self.tracing_data_is_big_endian) {
+                Some(FieldValue::Signed(wakee_tid)) => wakee_tid as i32,
+                Some(FieldValue::Unsigned(wakee_tid)) => wakee_tid as i32,
+                _ => return,
+            },
+            _ => return,
+        };
+        if let Some(runqueue_latencies) = &mut self.runqueue_latencies {
+            runqueue_latencies.handle_wakeup(wakee_tid, timestamp);
+        }
+        let wakee = self
+            .threads
+            .threads
+            .get(&wakee_tid)
+            .map(|wakee| (wakee.profile_thread, wakee.profile_process));
+        let profile_timestamp = self.timestamp_converter.convert_time(timestamp);
+
+        // A waker which is filtered out doesn't get a thread of its own, so the
+        // marker only gets its IDs.
+        if !self.thread_matches(pid, tid) {
+            let waker_name = self.profile.intern_string(&format!("<{}>", tid));
+            let wakeup = PendingWakeup {
+                timestamp: profile_timestamp,
+                marker: WakeupMarker {
+                    waker_name,
+                    category: self.scheduling_category,
+                    waker_pid: pid,
+                    waker_tid: tid,
+                },
+                stack: None,
+            };
+            self.pending_wakeups.insert(wakee_tid, wakeup);
+            return;
+        }
+
+        let is_main = pid == tid;
+        let stack = match self.unwind_sample::<C>(&e, pid, timestamp) {
+            Some(stack) => stack,
+            None => return,
+        };
+        let process = self.processes.get_by_pid(pid, &mut self.profile);
+
+        let waker =
+            self.threads
+                .get_by_tid(tid, process.profile_process, is_main, &mut self.profile);
+        let waker_name = match &waker.name {
+            Some(name) => self.profile.intern_string(name),
+            None => self.profile.intern_string(&format!("<{}>", tid)),
+        };
+        let frames = self.stack_converter.convert_stack(stack);
+        let frames = process.symbols.add_inline_frames(frames, &mut self.profile);
+        let stack = match wakee {
+            Some((wakee_thread_handle, wakee_process_handle))
+                if wakee_process_handle == process.profile_process =>
+            {
+                self.profile
+                    .intern_stack_frames(wakee_thread_handle, frames.into_iter())
+            }
+            _ => {
+                let waker_thread_handle = waker.profile_thread;
+                let marker_handle = self.profile.add_marker(
+                    waker_thread_handle,
+                    MarkerTiming::Instant(profile_timestamp),
+                    WakingMarker {
+                        category: self.scheduling_category,
+                        wakee_tid,
+                    },
+                );
+                let stack = self
+                    .profile
+                    .intern_stack_frames(waker_thread_handle, frames.into_iter());
+                self.profile
+                    .set_marker_stack(waker_thread_handle, marker_handle, stack);
+                None
+            }
+        };
+
+        let wakeup = PendingWakeup {
+            timestamp: profile_timestamp,
+            marker: WakeupMarker {
+                waker_name,
+                category: self.scheduling_category,
+                waker_pid: pid,
+                waker_tid: tid,
+            },
+            stack,
+        };
+        self.pending_wakeups.insert(wakee_tid, wakeup);
+    }
+
+    /// Get the pid, tid and timestamp of a sample or a context switch, or
+    /// count a warning if it doesn't have them.
+    fn pid_tid_timestamp(
+        &mut self,
+        pid: Option<i32>,
+        tid: Option<i32>,
+        timestamp: Option<u64>,
+    ) -> Option<(i32, i32, u64)> {
+        let (pid, tid) = match (pid, tid) {
+            (Some(pid), Some(tid)) => (pid, tid),
+            _ => {
+                self.warnings.add(Warning::MissingPidOrTid);
+                return None;
+            }
+        };
+        match timestamp {
+            Some(timestamp) => Some((pid, tid, timestamp)),
+            None => {
+                self.warnings.add(Warning::MissingTimestamp);
+                None
+            }
+        }
+    }
+
+    /// Get the stack of a sample in the process `pid`, after adding the
+    /// process's JIT code up to the sample's timestamp so that the stack can
+    /// be unwound through it. Counts a warning if the stack can't be unwound.
+    fn unwind_sample<C: ConvertRegs<UnwindRegs = U::UnwindRegs>>(
+        &mut self,
+        e: &SampleRecord,
+        pid: i32,
+        timestamp: u64,
+    ) -> Option<Vec<StackFrame>> {
+        let process = self.processes.get_by_pid(pid, &mut self.profile);
+        process.add_jit_code_until(timestamp, &mut self.profile);
+
+        let mut stack = Vec::new();
+        match Self::get_sample_stack::<C>(e, &process.unwinder, &mut self.cache, &mut stack) {
+            Ok(()) => Some(stack),
+            Err(warning) => {
+                self.warnings.add(warning);
+                None
+            }
+        }
+    }
+
+    /// Get the stack contained in this sample, and put it into `stack`.
+    ///
+    /// We can have both the kernel stack and the user stack, or just one of
+    /// them, or neither.
+    ///
+    /// If this sample has a kernel stack, it's always in `e.callchain`.
+    ///
+    /// If this sample has a user stack, its source depends on the method of
+    /// stackwalking that was requested during recording:
+    ///
+    ///  - With frame pointer unwinding (the default on x86, `perf record -g`,
+    ///    or more explicitly `perf record --call-graph fp`), the user stack
+    ///    is walked during sampling by the kernel and appended to e.callchain.
+    ///  - With DWARF unwinding (`perf record --call-graph dwarf`), the raw
+    ///    bytes on the stack are just copied into the perf.data file, and we
+    ///    need to do the unwinding now, based on the register values in
+    ///    `e.user_regs` and the raw stack bytes in `e.user_stack`.
+    ///
+    /// Returns an error if the sample has user registers but is missing some
+    /// of the registers which are needed for unwinding.
+    fn get_sample_stack<C: ConvertRegs<UnwindRegs = U::UnwindRegs>>(
+        e: &SampleRecord,
+        unwinder: &U,
+        cache: &mut U::Cache,
+        stack: &mut Vec<StackFrame>,
+    ) -> Result<(), Warning> {
+        stack.truncate(0);
+
+        // CpuMode::from_misc(e.raw.misc)
+
+        // Get the first fragment of the stack from e.callchain.
+        if let Some(callchain) = e.callchain {
+            let mut is_first_frame = true;
+            let mut mode = StackMode::from(e.cpu_mode);
+            for i in 0..callchain.len() {
+                let address = callchain.get(i).unwrap();
+                if address >= PERF_CONTEXT_MAX {
+                    if let Some(new_mode) = StackMode::from_context_frame(address) {
+                        mode = new_mode;
+                    }
+                    continue;
+                }
+
+                let stack_frame = match is_first_frame {
+                    true => StackFrame::InstructionPointer(address, mode),
+                    false => StackFrame::ReturnAddress(address, mode),
+                };
+                stack.push(stack_frame);
+
+                is_first_frame = false;
+            }
+        }
+
+        // Append the user stack with the help of DWARF unwinding.
+        if let (Some(regs), Some((user_stack, _))) = (&e.user_regs, e.user_stack) {
+            let ustack_bytes = RawDataU64::from_raw_data::<LittleEndian>(user_stack);
+            let (pc, sp, regs) = C::convert_regs(regs).ok_or(Warning::MissingRegisters)?;
+            let mut read_stack = |addr: u64| {
+                // ustack_bytes has the stack bytes starting from the current stack pointer.
+                let offset = addr.checked_sub(sp).ok_or(())?;
+                let index = usize::try_from(offset / 8).map_err(|_| ())?;
+                ustack_bytes.get(index).ok_or(())
+            };
+
+            // Unwind.
+            let mut frames = unwinder.iter_frames(pc, regs, cache, &mut read_stack);
+            loop {
+                let frame = match frames.next() {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => break,
+                    Err(_) => {
+                        stack.push(StackFrame::TruncatedStackMarker);
+                        break;
+                    }
+                };
+                let stack_frame = match frame {
+                    FrameAddress::InstructionPointer(addr) => {
+                        StackFrame::InstructionPointer(addr, StackMode::User)
+                    }
+                    FrameAddress::ReturnAddress(addr) => {
+                        StackFrame::ReturnAddress(addr.into(), StackMode::User)
+                    }
+                };
+                stack.push(stack_frame);
+            }
+        }
+
+        if stack.is_empty() {
+            if let Some(ip) = e.ip {
+                stack.push(StackFrame::InstructionPointer(ip, e.cpu_mode.into()));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn handle_mmap(&mut self, e: MmapRecord) {
+        if !e.is_executable {
+            return;
+        }
+
+        let mut path = e.path.as_slice();
+        let dso_key = match DsoKey::detect(&path, e.cpu_mode) {
+            Some(dso_key) => dso_key,
+            None => return,
+        };
+        let mut build_id = None;
+        if let Some(dso_info) = self.build_ids.get(&dso_key) {
+            build_id = Some(&dso_info.build_id[..]);
+            // Overwrite the path from the mmap record with the path from the build ID info.
+            // These paths are usually the same, but in some cases the path from the build
+            // ID info can be "better". For example, the synthesized mmap event for the
+            // kernel vmlinux image usually has "[kernel.kallsyms]_text" whereas the build
+            // ID info might have the full path to a kernel debug file, e.g.
+            // "/usr/lib/debug/boot/vmlinux-4.16.0-1-amd64".
+            path = Cow::Borrowed(&dso_info.path);
+        }
+
+        if e.pid == -1 {
+            let debug_id = build_id.map(|id| DebugId::from_identifier(id, self.little_endian));
+            let path = match std::str::from_utf8(&path) {
+                Ok(path) => path.to_string(),
+                Err(_) => {
+                    self.warnings.add(Warning::NonUtf8Path);
+                    return;
+                }
+            };
+            let mut debug_path = path.clone();
+            if debug_path.starts_with("[kernel.kallsyms]") {
+                if let Some(linux_version) = self.linux_version.as_deref() {
+                    // Take a guess at the vmlinux debug file path.
+                    debug_path = format!("/usr/lib/debug/boot/vmlinux-{}", linux_version);
+                }
+            }
+
+            let lib = self.profile.add_lib(LibraryInfo {
+                debug_id: debug_id.unwrap_or_default(),
+                path,
+                debug_path,
+                code_id: build_id.map(|id| CodeId::from_binary(id).to_string()),
+                name: dso_key.name().to_string(),
+                debug_name: dso_key.name().to_string(),
+                arch: None,
+                symbol_table: None,
+            });
+            self.profile
+                .add_kernel_lib_mapping(lib, e.address, e.address + e.length, 0);
+        } else {
+            let path = match std::str::from_utf8(&path) {
+                Ok(path) => path,
+                Err(_) => {
+                    self.warnings.add(Warning::NonUtf8Path);
+                    return;
+                }
+            };
+            if is_anonymous_mapping(path) {
+                return;
+            }
+            let mapping = ProcessMapping {
+                path: path.to_string(),
+                page_offset: e.page_offset,
+                address: e.address,
+                length: e.length,
+                build_id: build_id.map(<[u8]>::to_vec),
+            };
+            self.handle_process_mapping(e.pid, mapping);
+        }
+    }
+
+    pub fn handle_mmap2(&mut self, e: Mmap2Record) {
+        const PROT_EXEC: u32 = 0b100;
+        if e.protection & PROT_EXEC == 0 {
+            // Ignore non-executable mappings.
+            return;
+        }
+
+        let path = e.path.as_slice();
+        let build_id = match &e.file_id {
+            Mmap2FileId::BuildId(build_id) => Some(&build_id[..]),
+            Mmap2FileId::InodeAndVersion(_) => {
+                let dso_key = match DsoKey::detect(&path, e.cpu_mode) {
+                    Some(dso_key) => dso_key,
+                    None => return,
+                };
+                self.build_ids.get(&dso_key).map(|db| &db.build_id[..])
+            }
+        };
+        let path = match std::str::from_utf8(&path) {
+            Ok(path) => path,
+            Err(_) => {
+                self.warnings.add(Warning::NonUtf8Path);
+                return;
+            }
+        };
+        if is_anonymous_mapping(path) {
+            return;
+        }
+        let mapping = ProcessMapping {
+            path: path.to_string(),
+            page_offset: e.page_offset,
+            address: e.address,
+            length: e.length,
+            build_id: build_id.map(<[u8]>::to_vec),
+        };
+        self.handle_process_mapping(e.pid, mapping);
+    }
+
+    /// Add a file mapping to the process `pid`. If the task filter needs a
+    /// matching thread, the process's mappings are kept back until one of its
+    /// threads matches, so that processes which are filtered out are never
+    /// created and their binaries are never read.
+    fn handle_process_mapping(&mut self, pid: i32, mapping: ProcessMapping) {
+        if !self.task_filter.matches_process(pid) {
+            return;
+        }
+        if self.task_filter.filters_threads() && !self.processes.processes.contains_key(&pid) {
+            self.pending_mappings.entry(pid).or_default().push(mapping);
+            return;
+        }
+        self.add_process_mapping(pid, mapping);
+    }
+
+    /// Check whether the thread passes the task filter. When the first thread
+    /// of a process passes, the mappings which were kept back for the process
+    /// are added.
+    fn thread_matches(&mut self, pid: i32, tid: i32) -> bool {
+        if !self.task_filter.matches_thread(pid, tid) {
+            return false;
+        }
+        if let Some(mappings) = self.pending_mappings.remove(&pid) {
+            for mapping in mappings {
+                self.add_process_mapping(pid, mapping);
+            }
+        }
+        true
+    }
+
+    fn add_process_mapping(&mut self, pid: i32, mapping: ProcessMapping) {
+        let ProcessMapping {
+            path,
+            page_offset,
+            address,
+            length,
+            build_id,
+        } = mapping;
+        if is_jitdump_path(&path) {
+            self.handle_jitdump_mmap(pid, &path);
+            return;
+        }
+
+        let process = self.processes.get_by_pid(pid, &mut self.profile);
+        let process_root = process
+            .root
+            .get_or_insert_with(|| self.binary_lookup.process_root(pid));
+        if let Some((lib, base_avma, symbols)) = add_module_to_unwinder(
+            &mut process.unwinder,
+            &path,
+            page_offset,
+            address,
+            length,
+            build_id.as_deref(),
+            &self.binary_lookup,
+            &mut self.binary_cache,
+            process_root,
+            self.verbosity,
+            self.symbolicate,
+        ) {
+            let relative_address_at_start = match address
+                .checked_sub(base_avma)
+                .and_then(|offset| u32::try_from(offset).ok())
+            {
+                Some(relative_address) => relative_address,
+                None => {
+                    self.warnings.add(Warning::MappingOutOfRange);
+                    return;
+                }
+            };
+            if let Some(symbols) = symbols {
+                process
+                    .symbols
+                    .add_mapping(address..address + length, base_avma, symbols);
+            }
+            let lib = self.profile.add_lib(lib);
+            process.lib_mappings.add(
+                lib,
+                address,
+                address + length,
+                relative_address_at_start,
+                &mut self.profile,
+            );
+        }
+    }
+
+    /// Read the jitdump file which the process mmap'ed. The JIT code from the
+    /// file is added to the process when the conversion reaches the code's
+    /// timestamps. This requires the samples and the jitdump to use the same
+    /// clock, i.e. `perf record -k mono`; with other clocks, all the JIT code
+    /// is added right away.
+    fn handle_jitdump_mmap(&mut self, pid: i32, path: &str) {
+        let process = self.processes.get_by_pid(pid, &mut self.profile);
+        let process_root = process
+            .root
+            .get_or_insert_with(|| self.binary_lookup.process_root(pid));
+        let records = match self
+            .binary_lookup
+            .open(Path::new(path), Some(process_root))
+            .and_then(|mut file| {
+                let mut data = Vec::new();
+                file.read_to_end(&mut data)?;
+                Ok(data)
+            }) {
+            Ok(data) => match parse_jitdump(&data) {
+                Some(records) => records,
+                None => {
+                    if self.verbosity >= Verbosity::Normal {
+                        eprintln!("File {:?} is not a valid jitdump file", path);
+                    }
+                    return;
+                }
+            },
+            Err(_) => {
+                if self.verbosity >= Verbosity::Verbose {
+                    eprintln!("Could not open jitdump file {:?}", path);
+                }
+                return;
+            }
+        };
+        let (blobs, symbols) = code_blobs_and_symbols(records);
+        let name = Path::new(path)
+            .file_name()
+            .map_or("<jitdump>".into(), |f| f.to_string_lossy().to_string());
+        let lib = self.profile.add_lib(LibraryInfo {
+            name: name.clone(),
+            debug_name: name,
+            path: path.to_string(),
+            debug_path: path.to_string(),
+            debug_id: DebugId::nil(),
+            code_id: None,
+            arch: None,
+            symbol_table: Some(Arc::new(SymbolTable::new(symbols))),
+        });
+        let process = self.processes.get_by_pid(pid, &mut self.profile);
+        process.jit_code.add_jitdump(lib, blobs);
+        if !self.interpretation.clock_is_monotonic {
+            process.add_jit_code_until(u64::MAX, &mut self.profile);
+        }
+    }
+
+    pub fn handle_context_switch(&mut self, e: ContextSwitchRecord, common: CommonData) {
+        let (pid, tid, timestamp) =
+            match self.pid_tid_timestamp(common.pid, common.tid, common.timestamp) {
+                Some(ids) => ids,
+                None => return,
+            };
+        if !self.thread_matches(pid, tid) {
+            return;
+        }
+        let is_main = pid == tid;
+        let process = self.processes.get_by_pid(pid, &mut self.profile);
+        let process_handle = process.profile_process;
+        let thread = self
+            .threads
+            .get_by_tid(tid, process_handle, is_main, &mut self.profile);
+
+        if let (Some(cpus), Some(cpu)) = (&mut self.cpus, common.cpu) {
+            let time = self.timestamp_converter.convert_time(timestamp);
+            let thread_name = match &thread.name {
+                Some(name) => Cow::Borrowed(name.as_str()),
+                None => Cow::Owned(format!("<{}>", tid)),
+            };
+            match e {
+                ContextSwitchRecord::In { .. } => {
+                    cpus.handle_switch_in(cpu, time, pid, tid, &thread_name, &mut self.profile)
+                }
+                ContextSwitchRecord::Out { .. } => {
+                    cpus.handle_switch_out(cpu, time, pid, tid, &thread_name, &mut self.profile)
+                }
+            }
+        }
+
+        match e {
+            ContextSwitchRecord::In { .. } => {
+                let wakeup_timestamp = self
+                    .runqueue_latencies
+                    .as_mut()
+                    .and_then(|latencies| latencies.handle_switch_in(pid, tid, timestamp));
+                if let Some(wakeup_timestamp) = wakeup_timestamp {
+                    let start = self.timestamp_converter.convert_time(wakeup_timestamp);
+                    let end = self.timestamp_converter.convert_time(timestamp);
+                    self.profile.add_marker(
+                        thread.profile_thread,
+                        MarkerTiming::Interval(start, end),
+                        RunqueueLatencyMarker {
+                            category: self.scheduling_category,
+                        },
+                    );
+                }
+                let off_cpu_sample = self
+                    .context_switch_handler
+                    .handle_switch_in(timestamp, &mut thread.context_switch_data);
+                if let Some(off_cpu_sample) = off_cpu_sample {
+                    let cpu_delta_ns = self
+                        .context_switch_handler
+                        .consume_cpu_delta(&mut thread.context_switch_data);
+                    let off_cpu_stack = self
+                        .off_cpu_root_frames
+                        .stack(off_cpu_sample.reason, &thread.off_cpu_stack);
+                    process_off_cpu_sample_group(
+                        off_cpu_sample,
+                        thread.profile_thread,
+                        cpu_delta_ns,
+                        &self.timestamp_converter,
+                        self.sample_weights,
+                        &mut thread.time_weights,
+                        off_cpu_stack,
+                        &mut self.profile,
+                    );
+                    if let Some(wakeup) = self.pending_wakeups.remove(&tid) {
+                        wakeup.add_marker(thread.profile_thread, &mut self.profile);
+                    }
+                }
+                // Clear the saved off-CPU stack.
+                thread.off_cpu_stack = Vec::new();
+            }
+            ContextSwitchRecord::Out { preempted, .. } => {
+                if let Some(runqueue_latencies) = &mut self.runqueue_latencies {
+                    runqueue_latencies.handle_switch_out(tid);
+                }
+                // A wakeup while the thread was still running, e.g. right
+                // before it would have blocked, doesn't end the off-CPU time
+                // which starts now.
+                self.pending_wakeups.remove(&tid);
+                let reason = match preempted {
+                    TaskWasPreempted::Yes => OffCpuReason::Preempted,
+                    TaskWasPreempted::No => OffCpuReason::Blocked,
+                };
+                self.context_switch_handler.handle_switch_out(
+                    timestamp,
+                    reason,
+                    &mut thread.context_switch_data,
+                );
+            }
+        }
+    }
+
+    pub fn handle_thread_start(&mut self, e: ForkOrExitRecord) {
+        self.task_filter.handle_fork(e.tid, e.ptid);
+        if !self.thread_matches(e.pid, e.tid) {
+            return;
+        }
+        let is_main = e.pid == e.tid;
+        let start_time = self.timestamp_converter.convert_time(e.timestamp);
+        let process = self.processes.get_by_pid(e.pid, &mut self.profile);
+        let process_handle = process.profile_process;
+        if is_main {
+            self.profile
+                .set_process_start_time(process_handle, start_time);
+        }
+        let thread = self
+            .threads
+            .get_by_tid(e.tid, process_handle, is_main, &mut self.profile);
+        let thread_handle = thread.profile_thread;
+        self.profile
+            .set_thread_start_time(thread_handle, start_time);
+    }
+
+    pub fn handle_thread_end(&mut self, e: ForkOrExitRecord) {
+        if e.pid == e.tid {
+            self.pending_mappings.remove(&e.pid);
+        }
+        if !self.thread_matches(e.pid, e.tid) {
+            return;
+        }
+        let is_main = e.pid == e.tid;
+        let end_time = self.timestamp_converter.convert_time(e.timestamp);
+        let process = self.processes.get_by_pid(e.pid, &mut self.profile);
+        let process_handle = process.profile_process;
+        let thread = self
+            .threads
+            .get_by_tid(e.tid, process_handle, is_main, &mut self.profile);
+        let thread_handle = thread.profile_thread;
+        self.profile.set_thread_end_time(thread_handle, end_time);
+        for other_event_thread in thread.other_event_threads.values() {
+            self.profile
+                .set_thread_end_time(*other_event_thread, end_time);
+        }
+        Self::end_open_tracepoint_intervals(
+            thread,
+            &mut self.profile,
+            &self.tracepoint_pair_marker_types,
+            self.tracepoint_category,
+        );
+        self.threads.threads.remove(&e.tid);
+        self.pending_wakeups.remove(&e.tid);
+        if is_main {
+            self.profile.set_process_end_time(process_handle, end_time);
+            self.processes.processes.remove(&e.pid);
+        }
+    }
+
+    pub fn handle_thread_name_update(&mut self, e: CommOrExecRecord, timestamp: Option<u64>) {
+        let is_main = e.pid == e.tid;
+        if e.is_execve {
+            // Mark the old thread / process as ended.
+            // If the COMM record doesn't have a timestamp, take the last seen
+            // timestamp from the previous sample.
+            let timestamp = match timestamp {
+                Some(0) | None => self.current_sample_time,
+                Some(ts) => ts,
+            };
+            let time = self.timestamp_converter.convert_time(timestamp);
+            if let Some(t) = self.threads.threads.get_mut(&e.tid) {
+                self.profile.set_thread_end_time(t.profile_thread, time);
+                for other_event_thread in t.other_event_threads.values() {
+                    self.profile.set_thread_end_time(*other_event_thread, time);
+                }
+                Self::end_open_tracepoint_intervals(
+                    t,
+                    &mut self.profile,
+                    &self.tracepoint_pair_marker_types,
+                    self.tracepoint_category,
+                );
+                self.threads.threads.remove(&e.tid);
+            }
+            if is_main {
+                if let Some(p) = self.processes.processes.get(&e.pid) {
+                    self.profile.set_process_end_time(p.profile_process, time);
+                    self.processes.processes.remove(&e.pid);
+                }
+                // The kept back mappings are of the old executable.
+                self.pending_mappings.remove(&e.pid);
+            }
+        }
+
+        let name = e.name.as_slice();
+        let name = String::from_utf8_lossy(&name);
+        self.task_filter.handle_name_update(e.tid, &name);
+        if !self.thread_matches(e.pid, e.tid) {
+            return;
+        }
+
+        let process_handle = self
+            .processes
+            .get_by_pid(e.pid, &mut self.profile)
+            .profile_process;
+
+        let thread = self
+            .threads
+            .get_by_tid(e.tid, process_handle, is_main, &mut self.profile);
+        let thread_handle = thread.profile_thread;
+
+        self.profile.set_thread_name(thread_handle, &name);
+        for (attr_index, other_event_thread) in &thread.other_event_threads {
+            if let Some(event) = self.interpretation.other_sampled_event(*attr_index) {
+                self.profile.set_thread_name(
+                    *other_event_thread,
+                    &other_event_thread_name(Some(&name), &event.name),
+                );
+            }
+        }
+        thread.name = Some(name.to_string());
+        if is_main {
+            let process_name = process_display_name(&name, self.processes.label.as_deref());
+            self.profile.set_process_name(process_handle, &process_name);
+            if let Some(runqueue_latencies) = &mut self.runqueue_latencies {
+                runqueue_latencies.set_process_name(e.pid, &name);
+            }
+        }
+
+        if e.is_execve {
+            // Mark this as the start time of the new thread / process.
+            let time = self
+                .timestamp_converter
+                .convert_time(self.current_sample_time);
+            self.profile.set_thread_start_time(thread_handle, time);
+            if is_main {
+                self.profile.set_process_start_time(process_handle, time);
+            }
+        }
+
+        if !self.have_product_name && name != "perf-exec" {
+            let product = format!(
+                "{} on {} (perf version {})",
+                name, self.host, self.perf_version
+            );
+            self.profile.set_product(&product);
+            self.have_product_name = true;
+        }
+    }
+}
+
+struct TimestampConverter {
+    /// The perf timestamp which is `offset_ns` after the start of the profile.
+    reference_ns: u64,
+    offset_ns: u64,
+}
+
+impl TimestampConverter {
+    pub fn convert_time(&self, ktime_ns: u64) -> Timestamp {
+        Timestamp::from_nanos_since_reference(
+            ktime_ns.saturating_sub(self.reference_ns) + self.offset_ns,
+        )
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn process_off_cpu_sample_group(
+    off_cpu_sample: OffCpuSampleGroup,
+    thread_handle: ThreadHandle,
+    cpu_delta_ns: u64,
+    timestamp_converter: &TimestampConverter,
+    sample_weights: SampleWeights,
+    time_weights: &mut TimeWeights,
+    off_cpu_stack: impl Iterator<Item = FrameInfo>,
+    profile: &mut Profile,
+) {
+    let OffCpuSampleGroup {
+        begin_timestamp,
+        end_timestamp,
+        sample_count,
+        ..
+    } = off_cpu_sample;
+
+    // Add a sample at the beginning of the paused range.
+    // This "first sample" will carry any leftover accumulated running time ("cpu delta").
+    let cpu_delta = CpuDelta::from_nanos(cpu_delta_ns);
+    let weight = sample_weights.off_cpu_weight(1, time_weights);
+    let stack = profile.intern_stack_frames(thread_handle, off_cpu_stack);
+    let profile_timestamp = timestamp_converter.convert_time(begin_timestamp);
+    profile.add_sample(thread_handle, profile_timestamp, stack, cpu_delta, weight);
+
+    if sample_count > 1 {
+        // Emit a "rest sample" with a CPU delta of zero covering the rest of the paused range.
+        let cpu_delta = CpuDelta::from_nanos(0);
+        let weight = sample_weights.off_cpu_weight(sample_count - 1, time_weights);
+        let profile_timestamp = timestamp_converter.convert_time(end_timestamp);
+        profile.add_sample(thread_handle, profile_timestamp, stack, cpu_delta, weight);
+    }
+}
+
+/// How the samples on the thread tracks are weighted.
+#[derive(Debug, Clone, Copy)]
+enum SampleWeights {
+    /// Each on-CPU sample has a weight of 1, and each off-CPU sample has a
+    /// weight which makes it comparable to the on-CPU samples. If the main
+    /// event isn't time-based, this weight is 0.
+    Count { off_cpu_weight_per_sample: i32 },
+    /// Each sample is weighted by the time it stands for, in milliseconds:
+    /// on-CPU samples by their CPU delta, and off-CPU samples by the off-CPU
+    /// sampling interval.
+    Time { off_cpu_sampling_interval_ns: u64 },
+}
+
+impl SampleWeights {
+    fn on_cpu_weight(&self, cpu_delta_ns: u64, time_weights: &mut TimeWeights) -> i32 {
+        match self {
+            SampleWeights::Count { .. } => 1,
+            SampleWeights::Time { .. } => time_weights.weight_for_duration(cpu_delta_ns),
+        }
+    }
+
+    fn off_cpu_weight(&self, sample_count: u64, time_weights: &mut TimeWeights) -> i32 {
+        match self {
+            SampleWeights::Count {
+                off_cpu_weight_per_sample,
+            } => i32::try_from(sample_count)
+                .unwrap_or(i32::MAX)
+                .saturating_mul(*off_cpu_weight_per_sample),
+            SampleWeights::Time {
+                off_cpu_sampling_interval_ns,
+            } => time_weights
+                .weight_for_duration(sample_count.saturating_mul(*off_cpu_sampling_interval_ns)),
+        }
+    }
+}
+
+/// Turns durations into whole milliseconds for the sample weights of one
+/// thread. The sample weights are integers, so the remainder of each duration
+/// is carried over to the next sample, which keeps the total weight of the
+/// thread's samples accurate.
+#[derive(Debug, Clone, Default)]
+struct TimeWeights {
+    remainder_ns: u64,
+}
+
+impl TimeWeights {
+    fn weight_for_duration(&mut self, duration_ns: u64) -> i32 {
+        let total_ns = self.remainder_ns.saturating_add(duration_ns);
+        self.remainder_ns = total_ns % 1_000_000;
+        i32::try_from(total_ns / 1_000_000).unwrap_or(i32::MAX)
+    }
+}
+
+struct Processes<U>
+where
+    U: Unwinder<Module = Module<Vec<u8>>> + Default,
+{
+    processes: HashMap<i32, Process<U>>,
+    /// The directories in which to look for `perf-<pid>.map` files.
+    perf_map_dirs: Vec<PathBuf>,
+    /// Appended to the process names, see `process_display_name`.
+    label: Option<String>,
+}
+
+impl<U> Processes<U>
+where
+    U: Unwinder<Module = Module<Vec<u8>>> + Default,
+{
+    pub fn get_by_pid(&mut self, pid: i32, profile: &mut Profile) -> &mut Process<U> {
+        let perf_map_dirs = &self.perf_map_dirs;
+        let label = self.label.as_deref();
+        self.processes.entry(pid).or_insert_with(|| {
+            let name = format!("<{}>", pid);
+            let name = process_display_name(&name, label);
+            let handle = profile.add_process(
+                &name,
+                pid as u32,
+                Timestamp::from_millis_since_reference(0.0),
+            );
+            let mut lib_mappings = ProcessLibMappings::new(handle);
+            if let Some(perf_map) = load_perf_map(pid, perf_map_dirs) {
+                let lib = profile.add_lib(perf_map.lib);
+                for (start_avma, end_avma, relative_address_at_start) in perf_map.mappings {
+                    lib_mappings.add(
+                        lib,
+                        start_avma,
+                        end_avma,
+                        relative_address_at_start,
+                        profile,
+                    );
+                }
+            }
+            Process {
+                profile_process: handle,
+                unwinder: U::default(),
+                symbols: ProcessSymbols::default(),
+                jit_code: ProcessJitCode::default(),
+                lib_mappings,
+                root: None,
+            }
+        })
+    }
+}
+
+/// The name of a process in the profile. If the profile is made from multiple
+/// files, the name has the host and the file name of the recording appended.
+fn process_display_name<'a>(name: &'a str, label: Option<&str>) -> Cow<'a, str> {
+    match label {
+        Some(label) => Cow::Owned(format!("{} ({})", name, label)),
+        None => Cow::Borrowed(name),
+    }
+}
+
+/// Whether the mmap'ed path is not backed by a file which we can open, as is
+/// the case for JIT code. Such mappings must not hide the mappings for the
+/// JIT code from the `perf-<pid>.map` file.
+fn is_anonymous_mapping(path: &str) -> bool {
+    path == "//anon" || path.starts_with("[anon:") || path.starts_with("/memfd:")
+}
+
+/// Read the symbols for JIT code from the process's `perf-<pid>.map` file, if
+/// there is one.
+fn load_perf_map(pid: i32, dirs: &[PathBuf]) -> Option<PerfMapLib> {
+    let path = find_perf_map_file(pid, dirs)?;
+    let contents = std::fs::read_to_string(&path).ok()?;
+    Some(PerfMapLib::new(&path, parse_perf_map(&contents)))
+}
+
+struct Threads {
+    threads: HashMap<i32, Thread>,
+    /// The weight type of the samples on the thread tracks.
+    samples_weight_type: WeightType,
+}
+
+impl Threads {
+    pub fn get_by_tid(
+        &mut self,
+        tid: i32,
+        process_handle: ProcessHandle,
+        is_main: bool,
+        profile: &mut Profile,
+    ) -> &mut Thread {
+        let samples_weight_type = &self.samples_weight_type;
+        self.threads.entry(tid).or_insert_with(|| {
+            let profile_thread = profile.add_thread(
+                process_handle,
+                tid as u32,
+                Timestamp::from_millis_since_reference(0.0),
+                is_main,
+            );
+            profile.set_thread_samples_weight_type(profile_thread, samples_weight_type.clone());
+            Thread {
+                profile_thread,
+                profile_process: process_handle,
+                context_switch_data: Default::default(),
+                last_sample_timestamp: None,
+                off_cpu_stack: Vec::new(),
+                name: None,
+                other_event_threads: HashMap::new(),
+                open_tracepoint_intervals: HashMap::new(),
+                time_weights: TimeWeights::default(),
+            }
+        })
+    }
+}
+
+struct Thread {
+    profile_thread: ThreadHandle,
+    profile_process: ProcessHandle,
+    context_switch_data: ThreadContextSwitchData,
+    last_sample_timestamp: Option<u64>,
+    off_cpu_stack: Vec<FrameInfo>,
+    name: Option<String>,
+    /// The thread tracks for the samples of sampled events other than the main
+    /// event, keyed by attribute index.
+    other_event_threads: HashMap<usize, ThreadHandle>,
+    /// The entry samples of tracepoint pairs which haven't been matched with
+    /// an exit sample yet, keyed by pair index. Entries can nest, e.g. for
+    /// uprobes on recursive functions, so the innermost entry is last.
+    open_tracepoint_intervals: HashMap<usize, Vec<OpenTracepointInterval>>,
+    /// The part of this thread's sampled time which is less than a millisecond
+    /// and hasn't been counted in the weight of a sample yet.
+    time_weights: TimeWeights,
+}
+
+/// A wakeup of a thread, which becomes a marker if the thread's off-CPU time
+/// which ended with this wakeup produces off-CPU samples.
+struct PendingWakeup {
+    timestamp: Timestamp,
+    marker: WakeupMarker,
+    stack: Option<StackHandle>,
+}
+
+impl PendingWakeup {
+    fn add_marker(self, thread_handle: ThreadHandle, profile: &mut Profile) {
+        let marker_handle = profile.add_marker(
+            thread_handle,
+            MarkerTiming::Instant(self.timestamp),
+            self.marker,
+        );
+        profile.set_marker_stack(thread_handle, marker_handle, self.stack);
+    }
+}
+
+/// A file mapping from an mmap or mmap2 record of a process.
+struct ProcessMapping {
+    path: String,
+    page_offset: u64,
+    address: u64,
+    length: u64,
+    build_id: Option<Vec<u8>>,
+}
+
+struct OpenTracepointInterval {
+    start: Timestamp,
+    field_values: Vec<MarkerFieldValue>,
+    stack: Option<StackHandle>,
+}
+
+fn other_event_thread_name(thread_name: Option<&str>, event_name: &str) -> String {
+    match thread_name {
+        Some(thread_name) => format!("{} ({})", thread_name, event_name),
+        None => event_name.to_string(),
+    }
+}
+
+struct Process<U> {
+    pub profile_process: ProcessHandle,
+    pub unwinder: U,
+    pub symbols: ProcessSymbols,
+    pub jit_code: ProcessJitCode,
+    pub lib_mappings: ProcessLibMappings,
+    /// Where the process's files are, in case it ran in a container. This is
+    /// looked up when the process's first binary is mapped.
+    pub root: Option<ProcessRoot>,
+}
+
+impl<U> Process<U>
+where
+    U: Unwinder<Module = Module<Vec<u8>>>,
+{
+    /// Add the JIT code which was created up to `timestamp`, so that samples
+    /// in this code can be unwound and attributed to the right function.
+    pub fn add_jit_code_until(&mut self, timestamp: u64, profile: &mut Profile) {
+        self.jit_code.add_code_until(
+            timestamp,
+            &mut self.unwinder,
+            profile,
+            &mut self.lib_mappings,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        time_range, Converter, SampleWeights, SharedProfile, TimeWeights, TimestampConverter,
+    };
+    use crate::convert_regs::ConvertRegsX86_64;
+    use crate::error::ConvertError;
+    use crate::event_interpretation::{EventInterpretation, TracepointEvent};
+    use crate::options::{ConversionOptions, TimeRangeBound, Verbosity};
+    use crate::task_filter::TaskFilter;
+    use crate::tracing_data::TracingData;
+    use framehop::x86_64::UnwinderX86_64;
+    use linux_perf_data::linux_perf_event_reader::{
+        CommOrExecRecord, CpuMode, Mmap2FileId, Mmap2Record, RawData, SampleRecord,
+    };
+    use std::collections::HashMap;
+    use std::time::SystemTime;
+
+    fn mmap2(pid: i32, path: &str) -> Mmap2Record<'_> {
+        Mmap2Record {
+            pid,
+            tid: pid,
+            address: 0x10000,
+            length: 0x1000,
+            page_offset: 0,
+            file_id: Mmap2FileId::BuildId(vec![0xab, 0xcd, 0xef, 0x01]),
+            protection: 0b101,
+            flags: 0,
+            cpu_mode: CpuMode::User,
+            path: RawData::Single(path.as_bytes()),
+        }
+    }
+
+    fn comm(pid: i32, name: &str) -> CommOrExecRecord<'_> {
+        CommOrExecRecord {
+            pid,
+            tid: pid,
+            name: RawData::Single(name.as_bytes()),
+            is_execve: false,
+        }
+    }
+
+    fn sample(pid: i32, ip: u64, cpu: u32) -> SampleRecord<'static> {
+        SampleRecord {
+            id: None,
+            addr: None,
+            stream_id: None,
+            raw: None,
+            ip: Some(ip),
+            timestamp: Some(1_000_000),
+            pid: Some(pid),
+            tid: Some(pid),
+            cpu: Some(cpu),
+            period: Some(1),
+            user_regs: None,
+            user_stack: None,
+            callchain: None,
+            phys_addr: None,
+            data_page_size: None,
+            code_page_size: None,
+            cpu_mode: CpuMode::User,
+        }
+    }
+
+    fn interpretation() -> EventInterpretation {
+        EventInterpretation {
+            main_event_attr_index: 0,
+            main_event_name: "cycles".to_string(),
+            sampling_is_time_based: None,
+            have_context_switches: false,
+            sched_switch_attr_index: None,
+            sched_wakeup: None,
+            other_sampled_events: Vec::new(),
+            tracepoint_events: Vec::new(),
+            tracepoint_pairs: Vec::new(),
+            clock_is_monotonic: false,
+        }
+    }
+
+    /// A converter which only converts the processes named "firefox".
+    fn converter(options: &ConversionOptions) -> Converter<UnwinderX86_64<Vec<u8>>> {
+        let interpretation = interpretation();
+        let shared = SharedProfile::new(SystemTime::UNIX_EPOCH, &interpretation, options);
+        converter_for(shared, interpretation, options)
+    }
+
+    /// Like `converter`, but adds to `shared`, like the converter of a file
+    /// which is merged with others.
+    fn converter_for(
+        shared: SharedProfile,
+        interpretation: EventInterpretation,
+        options: &ConversionOptions,
+    ) -> Converter<UnwinderX86_64<Vec<u8>>> {
+        Converter::new(
+            shared,
+            HashMap::new(),
+            TimestampConverter {
+                reference_ns: 0,
+                offset_ns: 0,
+            },
+            "host",
+            "perf",
+            None,
+            true,
+            Default::default(),
+            options,
+            interpretation,
+            TracingData::default(),
+            TaskFilter::new(&[], &[], Some("^firefox$")).unwrap(),
+            None,
+        )
+    }
+
+    #[test]
+    fn filtered_out_processes_are_not_created() {
+        let mut converter = converter(&ConversionOptions {
+            verbosity: Verbosity::Quiet,
+            ..Default::default()
+        });
+
+        // The mappings are only added once the process's name is known.
+        converter.handle_mmap2(mmap2(10, "/nonexistent/firefox"));
+        converter.handle_mmap2(mmap2(20, "/nonexistent/bash"));
+        assert!(converter.processes.processes.is_empty());
+        converter.handle_thread_name_update(comm(10, "firefox"), Some(1));
+        converter.handle_thread_name_update(comm(20, "bash"), Some(1));
+        assert!(converter.processes.processes.contains_key(&10));
+        assert!(!converter.processes.processes.contains_key(&20));
+        assert!(!converter.pending_mappings.contains_key(&10));
+        assert_eq!(converter.pending_mappings[&20].len(), 1);
+    }
+
+    #[test]
+    fn merged_files_share_tracepoint_marker_types() {
+        let options = ConversionOptions {
+            verbosity: Verbosity::Quiet,
+            ..Default::default()
+        };
+        let mut interpretation = interpretation();
+        interpretation.tracepoint_events.push(TracepointEvent {
+            attr_index: 1,
+            name: "syscalls:sys_enter_openat".to_string(),
+            tracepoint_id: 612,
+        });
+        let mut shared = SharedProfile::new(SystemTime::UNIX_EPOCH, &interpretation, &options);
+        let mut marker_types = Vec::new();
+        for _ in 0..2 {
+            let converter = converter_for(shared, interpretation.clone(), &options);
+            marker_types.push(converter.tracepoint_marker_types[&1].marker_type);
+            shared = converter.finish().0;
+        }
+        assert_eq!(marker_types[0], marker_types[1]);
+
+        let profile = serde_json::to_value(shared.into_profile()).unwrap();
+        let schemas = profile["meta"]["markerSchema"].as_array().unwrap();
+        let openat_schemas = schemas
+            .iter()
+            .filter(|schema| schema["name"] == "syscalls:sys_enter_openat");
+        assert_eq!(openat_schemas.count(), 1);
+    }
+
+    #[test]
+    fn time_range_bounds() {
+        use TimeRangeBound::{Absolute, SinceFirstSample};
+        assert_eq!(time_range(None, None, None).unwrap(), 0..u64::MAX);
+        assert_eq!(
+            time_range(Some(SinceFirstSample(10)), Some(Absolute(2000)), Some(1000)).unwrap(),
+            1010..2000
+        );
+        assert_eq!(
+            time_range(Some(Absolute(1500)), None, None).unwrap(),
+            1500..u64::MAX
+        );
+        assert!(matches!(
+            time_range(
+                Some(Absolute(2000)),
+                Some(SinceFirstSample(500)),
+                Some(1000)
+            ),
+            Err(ConvertError::EmptyTimeRange {
+                start: 2000,
+                end: 1500
+            })
+        ));
+        assert!(matches!(
+            time_range(None, Some(SinceFirstSample(500)), None),
+            Err(ConvertError::UnknownFirstSampleTime)
+        ));
+    }
+
+    #[test]
+    fn off_cpu_weights_saturate() {
+        let mut time_weights = TimeWeights::default();
+        let count = SampleWeights::Count {
+            off_cpu_weight_per_sample: 1000,
+        };
+        assert_eq!(count.off_cpu_weight(3, &mut time_weights), 3000);
+        assert_eq!(count.off_cpu_weight(1 << 40, &mut time_weights), i32::MAX);
+        let time = SampleWeights::Time {
+            off_cpu_sampling_interval_ns: 1_500_000,
+        };
+        assert_eq!(time.off_cpu_weight(1, &mut time_weights), 1);
+        assert_eq!(time.off_cpu_weight(1, &mut time_weights), 2);
+        assert_eq!(time.off_cpu_weight(u64::MAX, &mut time_weights), i32::MAX);
+    }
+
+    #[test]
+    fn cpu_track_frames_are_resolved() {
+        let mut converter = converter(&ConversionOptions {
+            verbosity: Verbosity::Quiet,
+            cpu_tracks: true,
+            ..Default::default()
+        });
+        converter.handle_thread_name_update(comm(10, "firefox"), Some(1));
+        converter.handle_mmap2(mmap2(10, "/nonexistent/firefox"));
+        converter.handle_sample::<ConvertRegsX86_64>(sample(10, 0x10234, 3));
+
+        let profile = serde_json::to_value(&converter.profile).unwrap();
+        let thread = |name: &str| {
+            profile["threads"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|thread| thread["name"] == name)
+                .unwrap()
+        };
+        let cpu_thread = thread("CPU 3");
+        assert_eq!(
+            cpu_thread["frameTable"]["address"],
+            serde_json::json!([0x234])
+        );
+        let resource = cpu_thread["funcTable"]["resource"][0].as_u64().unwrap() as usize;
+        let lib = cpu_thread["resourceTable"]["lib"][resource]
+            .as_u64()
+            .unwrap() as usize;
+        assert_eq!(profile["libs"][lib]["name"], "firefox");
+        // The frame is the same as on the sampled thread.
+        let firefox_thread = thread("firefox");
+        assert_eq!(
+            cpu_thread["frameTable"]["address"],
+            firefox_thread["frameTable"]["address"]
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use fxprof_processed_profile::{
+    CategoryHandle, MarkerTiming, ProcessHandle, Profile, ThreadHandle, Timestamp,
+};
+
+use crate::markers::ThreadRunningMarker;
+
+/// One track per CPU, in a separate "CPUs" process. Each track has the samples
+/// which were taken on its CPU and, if the recording has context switch
+/// records, markers which show which thread was running on the CPU when.
+pub struct Cpus {
+    process: ProcessHandle,
+    category: CategoryHandle,
+    /// Indexed by CPU number. Tracks are created when a CPU is first seen.
+    cpus: Vec<Option<Cpu>>,
+}
+
+struct Cpu {
+    thread: ThreadHandle,
+    running_thread: Option<RunningThread>,
+}
+
+struct RunningThread {
+    start: Timestamp,
+    marker: ThreadRunningMarker,
+}
+
+impl Cpus {
+    pub fn new(profile: &mut Profile, name: &str, category: CategoryHandle) -> Self {
+        let process = profile.add_process(name, 0, Timestamp::from_millis_since_reference(0.0));
+        Self {
+            process,
+            category,
+            cpus: Vec::new(),
+        }
+    }
+
+    /// Returns the track of this CPU, and creates it if needed.
+    pub fn thread_for_cpu(&mut self, cpu: u32, profile: &mut Profile) -> ThreadHandle {
+        self.get_or_create_cpu(cpu, profile).thread
+    }
+
+    fn get_or_create_cpu(&mut self, cpu: u32, profile: &mut Profile) -> &mut Cpu {
+        let index = cpu as usize;
+        if self.cpus.len() <= index {
+            self.cpus.resize_with(index + 1, || None);
+        }
+        let process = self.process;
+        self.cpus[index].get_or_insert_with(|| {
+            let thread = profile.add_thread(
+                process,
+                cpu,
+                Timestamp::from_millis_since_reference(0.0),
+                false,
+            );
+            profile.set_thread_name(thread, &format!("CPU {}", cpu));
+            profile.set_thread_show_markers_in_timeline(thread, true);
+            Cpu {
+                thread,
+                running_thread: None,
+            }
+        })
+    }
+
+    /// A thread was switched in on this CPU. If we didn't see the previous
+    /// thread being switched out, it is assumed to have run until now.
+    pub fn handle_switch_in(
+        &mut self,
+        cpu: u32,
+        timestamp: Timestamp,
+        pid: i32,
+        tid: i32,
+        thread_name: &str,
+        profile: &mut Profile,
+    ) {
+        let marker = self.make_marker(pid, tid, thread_name, profile);
+        let cpu = self.get_or_create_cpu(cpu, profile);
+        if let Some(running_thread) = cpu.running_thread.take() {
+            profile.add_marker(
+                cpu.thread,
+                MarkerTiming::Interval(running_thread.start, timestamp),
+                running_thread.marker,
+            );
+        }
+        cpu.running_thread = Some(RunningThread {
+            start: timestamp,
+            marker,
+        });
+    }
+
+    /// A thread was switched out on this CPU.
+    pub fn handle_switch_out(
+        &mut self,
+        cpu: u32,
+        timestamp: Timestamp,
+        pid: i32,
+        tid: i32,
+        thread_name: &str,
+        profile: &mut Profile,
+    ) {
+        let marker = self.make_marker(pid, tid, thread_name, profile);
+        let cpu = self.get_or_create_cpu(cpu, profile);
+        let timing = match cpu.running_thread.take() {
+            Some(running_thread) if running_thread.marker.tid == tid => {
+                MarkerTiming::Interval(running_thread.start, timestamp)
+            }
+            // The thread was switched in before the recording started, or we
+            // missed the switch-in.
+            _ => MarkerTiming::IntervalEnd(timestamp),
+        };
+        profile.add_marker(cpu.thread, timing, marker);
+    }
+
+    fn make_marker(
+        &self,
+        pid: i32,
+        tid: i32,
+        thread_name: &str,
+        profile: &mut Profile,
+    ) -> ThreadRunningMarker {
+        ThreadRunningMarker {
+            name: profile.intern_string(thread_name),
+            category: self.category,
+            pid,
+            tid,
+        }
+    }
+
+    /// Add markers for the threads which were still running at the end of the
+    /// recording.
+    pub fn finish(&mut self, profile: &mut Profile) {
+        for cpu in self.cpus.iter_mut().flatten() {
+            if let Some(running_thread) = cpu.running_thread.take() {
+                profile.add_marker(
+                    cpu.thread,
+                    MarkerTiming::IntervalStart(running_thread.start),
+                    running_thread.marker,
+                );
+            }
+        }
+    }
+}
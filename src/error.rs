@@ -0,0 +1,34 @@
+/// The error type for a failed conversion.
+#[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
+pub enum ConvertError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Could not parse the perf.data file: {0}")]
+    Parse(#[from] linux_perf_data::Error),
+
+    #[error("The perf.data file does not say which architecture it was recorded on, so it can't be unwound")]
+    UnknownArch,
+
+    #[error("The main event {0} is not a sampled event")]
+    MainEventNotSampled(String),
+
+    #[error("The perf.data file does not contain an event named {0}")]
+    EventNotFound(String),
+
+    #[error("The perf.data file does not contain any sampled events")]
+    NoSampledEvent,
+
+    #[error("The time range to convert is empty: it starts at {start}ns and ends at {end}ns on the perf clock")]
+    EmptyTimeRange { start: u64, end: u64 },
+
+    #[error("The perf.data file does not record the time of its first sample, so the time range can only be given as perf timestamps, with \"@\"")]
+    UnknownFirstSampleTime,
+
+    #[error("No perf.data files were given")]
+    NoInputFiles,
+
+    #[error("Invalid regular expression for the thread name filter: {0}")]
+    InvalidCommFilter(#[source] regex::Error),
+}
@@ -0,0 +1,447 @@
+use linux_perf_data::linux_perf_event_reader::{
+    AttrFlags, ClockId, PerfClock, PerfEventAttr, PerfEventType, SamplingPolicy,
+    SoftwareCounterType,
+};
+use linux_perf_data::AttributeDescription;
+
+use crate::error::ConvertError;
+
+#[derive(Debug, Clone)]
+pub struct EventInterpretation {
+    pub main_event_attr_index: usize,
+    pub main_event_name: String,
+    pub sampling_is_time_based: Option<u64>,
+    pub have_context_switches: bool,
+    pub sched_switch_attr_index: Option<usize>,
+    /// The `sched:sched_waking` event, or `sched:sched_wakeup` if only that one
+    /// was recorded. Its samples are taken on the waking thread.
+    pub sched_wakeup: Option<TracepointEvent>,
+    pub other_sampled_events: Vec<OtherSampledEvent>,
+    pub tracepoint_events: Vec<TracepointEvent>,
+    pub tracepoint_pairs: Vec<TracepointPair>,
+    /// Whether the sample timestamps come from `CLOCK_MONOTONIC`, as requested
+    /// with `perf record -k mono`. This is the clock used in jitdump files.
+    pub clock_is_monotonic: bool,
+}
+
+/// A sampled event which isn't the main event, for example `instructions`
+/// when recording with `-e cycles -e instructions`. The samples of such an
+/// event are put on separate thread tracks, one per thread and event.
+#[derive(Debug, Clone)]
+pub struct OtherSampledEvent {
+    pub attr_index: usize,
+    pub name: String,
+    /// Whether each sample should be weighted by its period, i.e. by the number
+    /// of events it stands for. This is the case for all events other than the
+    /// clock events, so that the call tree shows event counts, even if the
+    /// period was adjusted by frequency-based sampling.
+    pub weight_by_period: bool,
+}
+
+/// A summary of one of the events in a perf.data file, for listing the events
+/// with [`list_events`](crate::list_events).
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct EventSummary {
+    /// The event name, e.g. "cycles" or "sched:sched_switch".
+    pub name: Option<String>,
+    /// The event type, e.g. "Hardware(CpuCycles, ...)" or "Tracepoint(316)".
+    pub event_type: String,
+    /// How the event was sampled, e.g. "4000 Hz" or "every 10000 events".
+    pub sampling_policy: String,
+    /// The attribute flags, e.g. "DISABLED | INHERIT | FREQ".
+    pub flags: String,
+    /// Whether this event is used as the main event if no event is chosen explicitly.
+    pub is_default_main_event: bool,
+}
+
+impl EventSummary {
+    pub fn from_attrs(attrs: &[AttributeDescription]) -> Vec<Self> {
+        let default_main_event_attr_index = default_main_event_attr_index(attrs);
+        attrs
+            .iter()
+            .enumerate()
+            .map(|(attr_index, attr_desc)| {
+                let sampling_policy = match attr_desc.attr.sampling_policy {
+                    SamplingPolicy::NoSampling => "no sampling".to_string(),
+                    SamplingPolicy::Frequency(freq) => format!("{} Hz", freq),
+                    SamplingPolicy::Period(period) => format!("every {} events", period),
+                };
+                Self {
+                    name: attr_desc.name.clone(),
+                    event_type: format!("{:?}", attr_desc.attr.type_),
+                    sampling_policy,
+                    flags: format!("{:?}", attr_desc.attr.flags),
+                    is_default_main_event: Some(attr_index) == default_main_event_attr_index,
+                }
+            })
+            .collect()
+    }
+}
+
+/// A tracepoint event which isn't the main event, e.g. `syscalls:sys_enter_openat`.
+/// Each sample of such an event becomes a marker.
+#[derive(Debug, Clone)]
+pub struct TracepointEvent {
+    pub attr_index: usize,
+    pub name: String,
+    pub tracepoint_id: u64,
+}
+
+/// A pair of entry and exit tracepoints, e.g. `syscalls:sys_enter_openat` and
+/// `syscalls:sys_exit_openat`, or an uprobe and its uretprobe, e.g.
+/// `probe_foo:bar` and `probe_foo:bar__return`. The entry and exit samples of
+/// each thread are matched up and become interval markers.
+#[derive(Debug, Clone)]
+pub struct TracepointPair {
+    pub name: String,
+    pub entry: TracepointEvent,
+    pub exit: TracepointEvent,
+}
+
+/// Whether a sample is the entry or the exit of a [`TracepointPair`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TracepointPairSide {
+    Entry,
+    Exit,
+}
+
+impl EventInterpretation {
+    /// Pick the main event and find out how to interpret the other events.
+    ///
+    /// If `main_event_name` is `None`, the first sampled event which is neither
+    /// a tracepoint nor the dummy event is used as the main event.
+    pub fn divine_from_attrs(
+        attrs: &[AttributeDescription],
+        main_event_name: Option<&str>,
+    ) -> Result<Self, ConvertError> {
+        let main_event_attr_index = match main_event_name {
+            Some(name) => attrs
+                .iter()
+                .position(|attr_desc| attr_desc.name.as_deref() == Some(name))
+                .ok_or_else(|| ConvertError::EventNotFound(name.to_string()))?,
+            None => default_main_event_attr_index(attrs).ok_or(ConvertError::NoSampledEvent)?,
+        };
+        let main_attr = &attrs[main_event_attr_index];
+        let main_event_name = event_name(main_attr).to_string();
+        let clock_is_monotonic =
+            matches!(main_attr.attr.clock, PerfClock::ClockId(ClockId::Monotonic));
+        let sampling_is_time_based = match main_attr.attr.sampling_policy {
+            SamplingPolicy::NoSampling => {
+                return Err(ConvertError::MainEventNotSampled(main_event_name));
+            }
+            _ => time_based_sampling_interval(&main_attr.attr),
+        };
+        // perf record --switch-events usually requests the context switch records
+        // on a separate dummy event, so check all events.
+        let have_context_switches = attrs
+            .iter()
+            .any(|attr_desc| attr_desc.attr.flags.contains(AttrFlags::CONTEXT_SWITCH));
+        let sched_switch_attr_index = attrs
+            .iter()
+            .position(|attr_desc| attr_desc.name.as_deref() == Some("sched:sched_switch"));
+        // sched_waking is emitted in the context of the waker, while sched_wakeup
+        // can be emitted on the CPU of the woken thread, so prefer sched_waking.
+        let sched_wakeup = ["sched:sched_waking", "sched:sched_wakeup"]
+            .iter()
+            .find_map(|name| {
+                attrs
+                    .iter()
+                    .position(|attr_desc| attr_desc.name.as_deref() == Some(name))
+            })
+            .and_then(|attr_index| match attrs[attr_index].attr.type_ {
+                PerfEventType::Tracepoint(tracepoint_id) => Some(TracepointEvent {
+                    attr_index,
+                    name: event_name(&attrs[attr_index]).to_string(),
+                    tracepoint_id,
+                }),
+                _ => None,
+            });
+        let sched_wakeup_attr_index = sched_wakeup.as_ref().map(|event| event.attr_index);
+        let other_sampled_events = attrs
+            .iter()
+            .enumerate()
+            .filter(|(attr_index, attr_desc)| {
+                *attr_index != main_event_attr_index
+                    && Some(*attr_index) != sched_switch_attr_index
+                    && !matches!(attr_desc.attr.sampling_policy, SamplingPolicy::NoSampling)
+                    // Tracepoints aren't sampled at a regular rate, so their
+                    // samples don't make for a meaningful call tree.
+                    && !matches!(attr_desc.attr.type_, PerfEventType::Tracepoint(_))
+            })
+            .map(|(attr_index, attr_desc)| OtherSampledEvent {
+                attr_index,
+                name: event_name(attr_desc).to_string(),
+                weight_by_period: !matches!(
+                    attr_desc.attr.type_,
+                    PerfEventType::Software(
+                        SoftwareCounterType::CpuClock | SoftwareCounterType::TaskClock
+                    )
+                ),
+            })
+            .collect();
+        let mut tracepoint_events: Vec<TracepointEvent> = attrs
+            .iter()
+            .enumerate()
+            .filter(|(attr_index, _)| {
+                *attr_index != main_event_attr_index
+                    && Some(*attr_index) != sched_switch_attr_index
+                    && Some(*attr_index) != sched_wakeup_attr_index
+            })
+            .filter_map(|(attr_index, attr_desc)| match attr_desc.attr.type_ {
+                PerfEventType::Tracepoint(tracepoint_id) => Some(TracepointEvent {
+                    attr_index,
+                    name: event_name(attr_desc).to_string(),
+                    tracepoint_id,
+                }),
+                _ => None,
+            })
+            .collect();
+        let mut tracepoint_pairs = Vec::new();
+        for entry_index in 0..tracepoint_events.len() {
+            let entry_name = &tracepoint_events[entry_index].name;
+            let (pair_name, exit_name) = match paired_exit_event_name(entry_name) {
+                Some(names) => names,
+                None => continue,
+            };
+            if let Some(exit) = tracepoint_events.iter().find(|e| e.name == exit_name) {
+                tracepoint_pairs.push(TracepointPair {
+                    name: pair_name,
+                    entry: tracepoint_events[entry_index].clone(),
+                    exit: exit.clone(),
+                });
+            }
+        }
+        tracepoint_events.retain(|event| {
+            !tracepoint_pairs.iter().any(|pair| {
+                pair.entry.attr_index == event.attr_index
+                    || pair.exit.attr_index == event.attr_index
+            })
+        });
+
+        Ok(Self {
+            main_event_attr_index,
+            main_event_name,
+            sampling_is_time_based,
+            have_context_switches,
+            sched_switch_attr_index,
+            sched_wakeup,
+            other_sampled_events,
+            tracepoint_events,
+            tracepoint_pairs,
+            clock_is_monotonic,
+        })
+    }
+
+    pub fn other_sampled_event(&self, attr_index: usize) -> Option<&OtherSampledEvent> {
+        self.other_sampled_events
+            .iter()
+            .find(|event| event.attr_index == attr_index)
+    }
+
+    pub fn is_sched_wakeup(&self, attr_index: usize) -> bool {
+        matches!(&self.sched_wakeup, Some(event) if event.attr_index == attr_index)
+    }
+
+    pub fn is_tracepoint_event(&self, attr_index: usize) -> bool {
+        self.tracepoint_events
+            .iter()
+            .any(|event| event.attr_index == attr_index)
+    }
+
+    /// Returns the index of the tracepoint pair which this event belongs to,
+    /// and whether it's the pair's entry or exit event.
+    pub fn tracepoint_pair(&self, attr_index: usize) -> Option<(usize, TracepointPairSide)> {
+        self.tracepoint_pairs
+            .iter()
+            .enumerate()
+            .find_map(|(pair_index, pair)| {
+                if pair.entry.attr_index == attr_index {
+                    Some((pair_index, TracepointPairSide::Entry))
+                } else if pair.exit.attr_index == attr_index {
+                    Some((pair_index, TracepointPairSide::Exit))
+                } else {
+                    None
+                }
+            })
+    }
+}
+
+/// If `name` looks like the entry event of an entry / exit pair, returns the
+/// name for the pair and the name of the exit event.
+///
+/// `syscalls:sys_enter_<name>` is paired with `syscalls:sys_exit_<name>`, and
+/// any other `<group>:<name>` is paired with `<group>:<name>__return`, which is
+/// how perf probe names uretprobes.
+fn paired_exit_event_name(name: &str) -> Option<(String, String)> {
+    if let Some(syscall) = name.strip_prefix("syscalls:sys_enter_") {
+        return Some((
+            format!("syscall {}", syscall),
+            format!("syscalls:sys_exit_{}", syscall),
+        ));
+    }
+    if name.ends_with("__return") || !name.contains(':') {
+        return None;
+    }
+    Some((name.to_string(), format!("{}__return", name)))
+}
+
+/// Returns the index of the first sampled event which is neither a tracepoint
+/// nor the dummy event, or of the first sampled event if there is no such event.
+fn default_main_event_attr_index(attrs: &[AttributeDescription]) -> Option<usize> {
+    let is_sampled = |attr_desc: &AttributeDescription| {
+        !matches!(attr_desc.attr.sampling_policy, SamplingPolicy::NoSampling)
+    };
+    attrs
+        .iter()
+        .position(|attr_desc| {
+            is_sampled(attr_desc)
+                && !matches!(
+                    attr_desc.attr.type_,
+                    PerfEventType::Tracepoint(_)
+                        | PerfEventType::Software(SoftwareCounterType::Dummy)
+                )
+        })
+        .or_else(|| attrs.iter().position(is_sampled))
+}
+
+fn event_name(attr_desc: &AttributeDescription) -> &str {
+    attr_desc.name.as_deref().unwrap_or("<unnamed event>")
+}
+
+/// Returns the sampling interval in nanoseconds if the samples of this event
+/// are taken at regular time intervals.
+fn time_based_sampling_interval(attr: &PerfEventAttr) -> Option<u64> {
+    match (attr.type_, attr.sampling_policy) {
+        (_, SamplingPolicy::NoSampling) => None,
+        (_, SamplingPolicy::Frequency(freq)) => {
+            let nanos = 1_000_000_000 / freq;
+            Some(nanos)
+        }
+        (
+            PerfEventType::Software(SoftwareCounterType::CpuClock | SoftwareCounterType::TaskClock),
+            SamplingPolicy::Period(period),
+        ) => {
+            // Assume that we're using a nanosecond clock. TODO: Check how we can know this for sure
+            let nanos = u64::from(period);
+            Some(nanos)
+        }
+        (_, SamplingPolicy::Period(_)) => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{default_main_event_attr_index, paired_exit_event_name, EventInterpretation};
+    use crate::error::ConvertError;
+    use linux_perf_data::linux_perf_event_reader::{
+        AttrFlags, BranchSampleFormat, HardwareEventId, PerfClock, PerfEventAttr, PerfEventType,
+        PmuTypeId, ReadFormat, SampleFormat, SamplingPolicy, SoftwareCounterType, WakeupPolicy,
+    };
+    use linux_perf_data::AttributeDescription;
+    use std::num::NonZeroU64;
+
+    fn event(
+        name: &str,
+        type_: PerfEventType,
+        sampling_policy: SamplingPolicy,
+    ) -> AttributeDescription {
+        AttributeDescription {
+            attr: PerfEventAttr {
+                type_,
+                sampling_policy,
+                sample_format: SampleFormat::empty(),
+                read_format: ReadFormat::empty(),
+                flags: AttrFlags::empty(),
+                wakeup_policy: WakeupPolicy::EventCount(1),
+                branch_sample_format: BranchSampleFormat::empty(),
+                sample_regs_user: 0,
+                sample_stack_user: 0,
+                clock: PerfClock::Default,
+                sample_regs_intr: 0,
+                aux_watermark: 0,
+                sample_max_stack: 0,
+                aux_sample_size: 0,
+                sig_data: 0,
+            },
+            name: Some(name.to_string()),
+            event_ids: Vec::new(),
+        }
+    }
+
+    /// The events of `perf record -e syscalls:sys_enter_openat -e cycles
+    /// -e instructions --switch-events`, with the dummy event first.
+    fn recorded_events() -> Vec<AttributeDescription> {
+        vec![
+            event(
+                "dummy:u",
+                PerfEventType::Software(SoftwareCounterType::Dummy),
+                SamplingPolicy::Period(NonZeroU64::new(1).unwrap()),
+            ),
+            event(
+                "syscalls:sys_enter_openat",
+                PerfEventType::Tracepoint(612),
+                SamplingPolicy::Period(NonZeroU64::new(1).unwrap()),
+            ),
+            event(
+                "cycles",
+                PerfEventType::Hardware(HardwareEventId::CpuCycles, PmuTypeId(0)),
+                SamplingPolicy::Frequency(4000),
+            ),
+            event(
+                "instructions",
+                PerfEventType::Hardware(HardwareEventId::Instructions, PmuTypeId(0)),
+                SamplingPolicy::Period(NonZeroU64::new(1_000_000).unwrap()),
+            ),
+        ]
+    }
+
+    #[test]
+    fn default_main_event() {
+        let events = recorded_events();
+        let interpretation = EventInterpretation::divine_from_attrs(&events, None).unwrap();
+        assert_eq!(interpretation.main_event_attr_index, 2);
+        assert_eq!(interpretation.main_event_name, "cycles");
+        assert_eq!(interpretation.sampling_is_time_based, Some(250_000));
+
+        // Without other events, the dummy event or a tracepoint is used.
+        assert_eq!(default_main_event_attr_index(&events[..2]), Some(0));
+        assert_eq!(default_main_event_attr_index(&events[1..2]), Some(0));
+    }
+
+    #[test]
+    fn main_event_by_name() {
+        let events = recorded_events();
+        let interpretation =
+            EventInterpretation::divine_from_attrs(&events, Some("instructions")).unwrap();
+        assert_eq!(interpretation.main_event_attr_index, 3);
+        assert_eq!(interpretation.sampling_is_time_based, None);
+        // The default main event becomes one of the other sampled events.
+        assert!(interpretation.other_sampled_event(2).is_some());
+
+        assert!(matches!(
+            EventInterpretation::divine_from_attrs(&events, Some("cache-misses")),
+            Err(ConvertError::EventNotFound(name)) if name == "cache-misses"
+        ));
+    }
+
+    #[test]
+    fn pair_entry_and_exit_names() {
+        assert_eq!(
+            paired_exit_event_name("syscalls:sys_enter_openat"),
+            Some((
+                "syscall openat".to_string(),
+                "syscalls:sys_exit_openat".to_string()
+            ))
+        );
+        assert_eq!(
+            paired_exit_event_name("probe_libc:malloc"),
+            Some((
+                "probe_libc:malloc".to_string(),
+                "probe_libc:malloc__return".to_string()
+            ))
+        );
+        assert_eq!(paired_exit_event_name("probe_libc:malloc__return"), None);
+        assert_eq!(paired_exit_event_name("cycles"), None);
+    }
+}
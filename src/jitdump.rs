@@ -0,0 +1,400 @@
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use framehop::{Module, ModuleSvmaInfo, ModuleUnwindData, TextByteData, Unwinder};
+use fxprof_processed_profile::{LibraryHandle, Profile, Symbol};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::stack::ProcessLibMappings;
+
+const JITDUMP_MAGIC: u32 = 0x4A695444; // "JiTD"
+const HEADER_SIZE: usize = 40;
+const RECORD_HEADER_SIZE: usize = 16;
+
+const JIT_CODE_LOAD: u32 = 0;
+const JIT_CODE_MOVE: u32 = 1;
+const JIT_CODE_UNWINDING_INFO: u32 = 4;
+
+/// Whether this is the path of a jitdump file. JIT compilers which write
+/// jitdump files mmap them, so that perf records the file name.
+pub fn is_jitdump_path(path: &str) -> bool {
+    let filename = match Path::new(path).file_name().and_then(|f| f.to_str()) {
+        Some(filename) => filename,
+        None => return false,
+    };
+    filename.starts_with("jit-") && filename.ends_with(".dump")
+}
+
+/// A record from a jitdump file, as written by V8 with `--perf-prof`, by
+/// Wasmtime, .NET, LuaJIT and others. Debug info records are not used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JitDumpRecord {
+    /// A function was compiled. The unwinding info is taken from the
+    /// unwinding info record which preceded this record, if any.
+    CodeLoad {
+        timestamp: u64,
+        code_index: u64,
+        name: String,
+        code_addr: u64,
+        code_bytes: Vec<u8>,
+        unwinding_info: Option<Arc<UnwindingInfo>>,
+    },
+    /// Previously loaded code was moved to a different address.
+    CodeMove {
+        timestamp: u64,
+        code_index: u64,
+        new_code_addr: u64,
+    },
+}
+
+/// The `.eh_frame` and `.eh_frame_hdr` for a piece of JIT code. They are laid
+/// out as if they were placed right after the code: first the `.eh_frame`,
+/// then the `.eh_frame_hdr`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnwindingInfo {
+    pub eh_frame: Vec<u8>,
+    pub eh_frame_hdr: Vec<u8>,
+}
+
+/// Parse the records of a jitdump file. If the file ends in the middle of a
+/// record, e.g. because the JIT was still writing it, the records before it
+/// are returned.
+pub fn parse_jitdump(data: &[u8]) -> Option<Vec<JitDumpRecord>> {
+    if data.len() < HEADER_SIZE {
+        return None;
+    }
+    if LittleEndian::read_u32(&data[0..4]) == JITDUMP_MAGIC {
+        parse_records::<LittleEndian>(data)
+    } else if BigEndian::read_u32(&data[0..4]) == JITDUMP_MAGIC {
+        parse_records::<BigEndian>(data)
+    } else {
+        None
+    }
+}
+
+fn parse_records<T: ByteOrder>(data: &[u8]) -> Option<Vec<JitDumpRecord>> {
+    let header_size = T::read_u32(&data[8..12]) as usize;
+    let mut offset = header_size.max(HEADER_SIZE);
+    let mut records = Vec::new();
+    let mut unwinding_info = None;
+    while offset + RECORD_HEADER_SIZE <= data.len() {
+        let id = T::read_u32(&data[offset..]);
+        let total_size = T::read_u32(&data[offset + 4..]) as usize;
+        let timestamp = T::read_u64(&data[offset + 8..]);
+        if total_size < RECORD_HEADER_SIZE || offset + total_size > data.len() {
+            break;
+        }
+        let body = &data[offset + RECORD_HEADER_SIZE..offset + total_size];
+        offset += total_size;
+        match id {
+            JIT_CODE_LOAD if body.len() >= 40 => {
+                // pid: u32, tid: u32, vma: u64, code_addr: u64, code_size: u64,
+                // code_index: u64, then the nul-terminated name and the code.
+                let code_addr = T::read_u64(&body[16..]);
+                let code_size = T::read_u64(&body[24..]) as usize;
+                let code_index = T::read_u64(&body[32..]);
+                let rest = &body[40..];
+                let name_len = match memchr::memchr(0, rest) {
+                    Some(name_len) => name_len,
+                    None => break,
+                };
+                let code_bytes = match rest.get(name_len + 1..name_len + 1 + code_size) {
+                    Some(code_bytes) => code_bytes.to_vec(),
+                    None => break,
+                };
+                records.push(JitDumpRecord::CodeLoad {
+                    timestamp,
+                    code_index,
+                    name: String::from_utf8_lossy(&rest[..name_len]).into_owned(),
+                    code_addr,
+                    code_bytes,
+                    unwinding_info: unwinding_info.take(),
+                });
+            }
+            JIT_CODE_MOVE if body.len() >= 48 => {
+                // pid: u32, tid: u32, vma: u64, old_code_addr: u64,
+                // new_code_addr: u64, code_size: u64, code_index: u64
+                records.push(JitDumpRecord::CodeMove {
+                    timestamp,
+                    code_index: T::read_u64(&body[40..]),
+                    new_code_addr: T::read_u64(&body[24..]),
+                });
+            }
+            JIT_CODE_UNWINDING_INFO if body.len() >= 24 => {
+                // unwinding_size: u64, eh_frame_hdr_size: u64, mapped_size: u64,
+                // then the unwinding data: the .eh_frame and the .eh_frame_hdr.
+                let unwinding_size = T::read_u64(&body[0..]) as usize;
+                let eh_frame_hdr_size = T::read_u64(&body[8..]) as usize;
+                let unwinding_data = match body[24..].get(..unwinding_size) {
+                    Some(unwinding_data) if eh_frame_hdr_size <= unwinding_size => unwinding_data,
+                    _ => continue,
+                };
+                let (eh_frame, eh_frame_hdr) =
+                    unwinding_data.split_at(unwinding_size - eh_frame_hdr_size);
+                unwinding_info = Some(Arc::new(UnwindingInfo {
+                    eh_frame: eh_frame.to_vec(),
+                    eh_frame_hdr: eh_frame_hdr.to_vec(),
+                }));
+            }
+            _ => {}
+        }
+    }
+    Some(records)
+}
+
+/// A piece of JIT code at a specific address, which becomes a module in the
+/// unwinder and a mapping of the jitdump's library in the profile, once the
+/// conversion reaches its timestamp.
+#[derive(Debug, Clone)]
+pub struct JitCodeBlob {
+    pub timestamp: u64,
+    pub name: Arc<str>,
+    pub avma: u64,
+    /// The address of the code within the jitdump's synthetic library.
+    pub relative_address: u32,
+    pub code_bytes: Arc<Vec<u8>>,
+    pub unwinding_info: Option<Arc<UnwindingInfo>>,
+}
+
+impl JitCodeBlob {
+    fn avma_range(&self) -> std::ops::Range<u64> {
+        self.avma..self.avma + self.code_bytes.len() as u64
+    }
+
+    fn to_module(&self) -> Module<Vec<u8>> {
+        let code_size = self.code_bytes.len() as u64;
+        let (eh_frame_svma, eh_frame_hdr_svma, unwind_data) = match &self.unwinding_info {
+            Some(info) => {
+                let eh_frame_end = code_size + info.eh_frame.len() as u64;
+                let eh_frame_hdr_end = eh_frame_end + info.eh_frame_hdr.len() as u64;
+                let unwind_data = if info.eh_frame_hdr.is_empty() {
+                    ModuleUnwindData::EhFrame(info.eh_frame.clone())
+                } else {
+                    ModuleUnwindData::EhFrameHdrAndEhFrame(
+                        info.eh_frame_hdr.clone(),
+                        info.eh_frame.clone(),
+                    )
+                };
+                (
+                    Some(code_size..eh_frame_end),
+                    Some(eh_frame_end..eh_frame_hdr_end),
+                    unwind_data,
+                )
+            }
+            None => (None, None, ModuleUnwindData::None),
+        };
+        Module::new(
+            self.name.to_string(),
+            self.avma_range(),
+            self.avma,
+            ModuleSvmaInfo {
+                base_svma: 0,
+                text: Some(0..code_size),
+                text_env: None,
+                stubs: None,
+                stub_helper: None,
+                eh_frame: eh_frame_svma,
+                eh_frame_hdr: eh_frame_hdr_svma,
+                got: None,
+            },
+            unwind_data,
+            Some(TextByteData::new(
+                self.code_bytes.to_vec(),
+                self.avma_range(),
+            )),
+        )
+    }
+}
+
+/// Turn the jitdump records into code blobs, in timestamp order, and create the
+/// symbols for the jitdump's synthetic library. Each loaded function gets its
+/// own range of relative addresses, which it keeps when it's moved.
+pub fn code_blobs_and_symbols(records: Vec<JitDumpRecord>) -> (Vec<JitCodeBlob>, Vec<Symbol>) {
+    let mut blobs: Vec<JitCodeBlob> = Vec::new();
+    let mut symbols = Vec::new();
+    let mut blob_index_by_code_index = HashMap::new();
+    let mut next_relative_address: u64 = 0;
+    for record in records {
+        match record {
+            JitDumpRecord::CodeLoad {
+                timestamp,
+                code_index,
+                name,
+                code_addr,
+                code_bytes,
+                unwinding_info,
+            } => {
+                let code_size = code_bytes.len() as u64;
+                // The relative addresses need to fit into 32 bits.
+                let relative_address = match u32::try_from(next_relative_address + code_size) {
+                    Ok(_) => next_relative_address as u32,
+                    Err(_) => break,
+                };
+                next_relative_address += code_size;
+                symbols.push(Symbol {
+                    address: relative_address,
+                    size: Some(code_size as u32),
+                    name: name.clone(),
+                });
+                blob_index_by_code_index.insert(code_index, blobs.len());
+                blobs.push(JitCodeBlob {
+                    timestamp,
+                    name: name.into(),
+                    avma: code_addr,
+                    relative_address,
+                    code_bytes: Arc::new(code_bytes),
+                    unwinding_info,
+                });
+            }
+            JitDumpRecord::CodeMove {
+                timestamp,
+                code_index,
+                new_code_addr,
+            } => {
+                if let Some(&blob_index) = blob_index_by_code_index.get(&code_index) {
+                    let moved_blob = JitCodeBlob {
+                        timestamp,
+                        avma: new_code_addr,
+                        ..blobs[blob_index].clone()
+                    };
+                    blob_index_by_code_index.insert(code_index, blobs.len());
+                    blobs.push(moved_blob);
+                }
+            }
+        }
+    }
+    blobs.sort_by_key(|blob| blob.timestamp);
+    (blobs, symbols)
+}
+
+/// The JIT code of one process, from the jitdump files which it mmap'ed.
+#[derive(Debug, Default)]
+pub struct ProcessJitCode {
+    /// The code blobs which haven't been added yet, in reverse timestamp order,
+    /// together with the library of their jitdump file.
+    pending: Vec<(LibraryHandle, JitCodeBlob)>,
+    /// The address ranges of the JIT code modules in the unwinder, as a map
+    /// from start address to end address.
+    modules: BTreeMap<u64, u64>,
+}
+
+impl ProcessJitCode {
+    pub fn add_jitdump(&mut self, lib: LibraryHandle, blobs: Vec<JitCodeBlob>) {
+        self.pending
+            .extend(blobs.into_iter().map(|blob| (lib, blob)));
+        self.pending
+            .sort_by_key(|(_, blob)| std::cmp::Reverse(blob.timestamp));
+    }
+
+    /// Add the code blobs up to `timestamp` to the unwinder and to the
+    /// process's library mappings. Older code at the same addresses is
+    /// replaced.
+    pub fn add_code_until<U>(
+        &mut self,
+        timestamp: u64,
+        unwinder: &mut U,
+        profile: &mut Profile,
+        lib_mappings: &mut ProcessLibMappings,
+    ) where
+        U: Unwinder<Module = Module<Vec<u8>>>,
+    {
+        while matches!(self.pending.last(), Some((_, blob)) if blob.timestamp <= timestamp) {
+            let (lib, blob) = self.pending.pop().unwrap();
+            let avma_range = blob.avma_range();
+            let overlapping_modules: Vec<u64> = self
+                .modules
+                .range(..avma_range.end)
+                .filter(|(_, end)| **end > avma_range.start)
+                .map(|(start, _)| *start)
+                .collect();
+            for start in overlapping_modules {
+                unwinder.remove_module(start);
+                self.modules.remove(&start);
+            }
+            unwinder.add_module(blob.to_module());
+            self.modules.insert(avma_range.start, avma_range.end);
+            lib_mappings.add(
+                lib,
+                avma_range.start,
+                avma_range.end,
+                blob.relative_address,
+                profile,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{code_blobs_and_symbols, is_jitdump_path, parse_jitdump, JitDumpRecord};
+
+    fn record(id: u32, timestamp: u64, body: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&id.to_le_bytes());
+        data.extend_from_slice(&(16 + body.len() as u32).to_le_bytes());
+        data.extend_from_slice(&timestamp.to_le_bytes());
+        data.extend_from_slice(body);
+        data
+    }
+
+    #[test]
+    fn parse_code_load_move_and_unwinding_info() {
+        assert!(is_jitdump_path("/home/user/.debug/jit/jit-1234.dump"));
+        assert!(!is_jitdump_path("/usr/lib/libc.so.6"));
+
+        let mut data = Vec::new();
+        data.extend_from_slice(&0x4A695444u32.to_le_bytes());
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&40u32.to_le_bytes());
+        data.resize(40, 0);
+
+        let mut unwinding_info = Vec::new();
+        for value in [6u64, 2, 8] {
+            unwinding_info.extend_from_slice(&value.to_le_bytes());
+        }
+        unwinding_info.extend_from_slice(b"efrmHD");
+        data.extend(record(4, 100, &unwinding_info));
+
+        let mut code_load = Vec::new();
+        for value in [0u64, 0x1000, 0x1000, 4, 7] {
+            code_load.extend_from_slice(&value.to_le_bytes());
+        }
+        code_load.extend_from_slice(b"JS:foo\0");
+        code_load.extend_from_slice(&[0x55, 0x48, 0x89, 0xe5]);
+        data.extend(record(0, 101, &code_load));
+
+        let mut code_move = Vec::new();
+        for value in [0u64, 0x1000, 0x1000, 0x2000, 4, 7] {
+            code_move.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend(record(1, 102, &code_move));
+        // A truncated record at the end is ignored.
+        data.extend(&record(0, 103, &code_load)[..30]);
+
+        let records = parse_jitdump(&data).unwrap();
+        assert_eq!(records.len(), 2);
+        match &records[0] {
+            JitDumpRecord::CodeLoad {
+                name,
+                code_addr,
+                code_bytes,
+                unwinding_info,
+                ..
+            } => {
+                assert_eq!(name, "JS:foo");
+                assert_eq!(*code_addr, 0x1000);
+                assert_eq!(code_bytes, &[0x55, 0x48, 0x89, 0xe5]);
+                let unwinding_info = unwinding_info.as_ref().unwrap();
+                assert_eq!(unwinding_info.eh_frame, b"efrm");
+                assert_eq!(unwinding_info.eh_frame_hdr, b"HD");
+            }
+            other => panic!("unexpected record {:?}", other),
+        }
+
+        let (blobs, symbols) = code_blobs_and_symbols(records);
+        assert_eq!(blobs.len(), 2);
+        assert_eq!((blobs[1].avma, blobs[1].relative_address), (0x2000, 0));
+        assert_eq!(symbols.len(), 1);
+        assert_eq!(symbols[0].name, "JS:foo");
+    }
+}
@@ -0,0 +1,272 @@
+//! A converter from the Linux perf `perf.data` format into the
+//! [Firefox Profiler](https://profiler.firefox.com/) format, specifically into
+//! the [processed profile format](https://crates.io/crates/fxprof-processed-profile).
+//!
+//! # Example
+//!
+//! ```no_run
+//! use fxprof_perf_convert::{convert_file, ConversionOptions};
+//! use std::path::Path;
+//!
+//! # fn wrapper() -> Result<(), Box<dyn std::error::Error>> {
+//! let mut options = ConversionOptions::default();
+//! options.off_cpu_sampling_interval_ns = Some(1_000_000);
+//! let profile = convert_file(Path::new("perf.data"), options)?;
+//! let file = std::fs::File::create("profile.json")?;
+//! serde_json::to_writer(std::io::BufWriter::new(file), &profile)?;
+//! # Ok(())
+//! # }
+//! ```
+
+mod binary_lookup;
+mod clock;
+mod context_switch;
+mod convert_regs;
+mod converter;
+mod cpus;
+mod error;
+mod event_interpretation;
+mod jitdump;
+mod markers;
+mod options;
+mod perf_map;
+mod runqueue_latency;
+mod stack;
+mod symbolication;
+mod task_filter;
+mod tracing_data;
+mod unwinding;
+mod warnings;
+
+pub use error::ConvertError;
+pub use event_interpretation::EventSummary;
+pub use options::{ContainerSelector, ConversionOptions, TimeRangeBound, Verbosity};
+
+/// This is a re-export of the fxprof-processed-profile crate. The converted
+/// [`Profile`] is one of its types.
+pub use fxprof_processed_profile;
+
+use convert_regs::{ConvertRegsAarch64, ConvertRegsX86_64};
+use converter::{RecordingInfo, SharedProfile};
+use fxprof_processed_profile::Profile;
+use linux_perf_data::PerfFileReader;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Convert the perf.data file at `path` into a profile.
+///
+/// If `options.extra_dir` is `None`, the directory containing the perf.data
+/// file is used as the extra directory in which binaries are looked up.
+///
+/// The profile's reference timestamp is the wall-clock time of the first
+/// sample, taken from the clock data in the file if present. Otherwise it is
+/// estimated from the file's modification time.
+pub fn convert_file(path: &Path, options: ConversionOptions) -> Result<Profile, ConvertError> {
+    convert_files(&[path], options)
+}
+
+/// Convert multiple perf.data files into a single profile, e.g. recordings of
+/// the same workload on different hosts.
+///
+/// The processes of each file get the host and the file name appended to their
+/// names. The files' samples are placed at their wall-clock times, like in
+/// [`convert_file`], unless `options.align_by_first_sample` is set, in which
+/// case the first sample of each file is at the start of the profile.
+///
+/// If `options.extra_dir` is `None`, binaries are looked up in the directory
+/// of the perf.data file which references them.
+pub fn convert_files<P: AsRef<Path>>(
+    paths: &[P],
+    options: ConversionOptions,
+) -> Result<Profile, ConvertError> {
+    let mut inputs = Vec::new();
+    for path in paths {
+        let path = path.as_ref().canonicalize()?;
+        let file = File::open(&path)?;
+        let file_mtime = file
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        inputs.push(Input {
+            reader: BufReader::new(file),
+            name: path
+                .file_name()
+                .map_or_else(String::new, |name| name.to_string_lossy().into_owned()),
+            extra_dir: path.parent().map(ToOwned::to_owned),
+            file_mtime,
+        });
+    }
+    convert_inputs(inputs, options)
+}
+
+/// Convert perf.data file contents, read from `reader`, into a profile.
+///
+/// If the file doesn't contain clock data, the profile's reference timestamp
+/// will be the current time.
+pub fn convert_perf_file<R: Read + Seek>(
+    reader: R,
+    options: ConversionOptions,
+) -> Result<Profile, ConvertError> {
+    let input = Input {
+        reader,
+        name: String::from("perf.data"),
+        extra_dir: None,
+        file_mtime: None,
+    };
+    convert_inputs(vec![input], options)
+}
+
+/// List the events which were recorded in the perf.data file read from `reader`.
+///
+/// This can be used to find the name to put into [`ConversionOptions::main_event`].
+pub fn list_events<R: Read + Seek>(reader: R) -> Result<Vec<EventSummary>, ConvertError> {
+    let perf_file = PerfFileReader::parse_file(reader)?;
+    Ok(EventSummary::from_attrs(
+        perf_file.perf_file.event_attributes(),
+    ))
+}
+
+/// A perf.data file which is about to be converted.
+struct Input<R> {
+    reader: R,
+    /// The file name, which is shown in the process names if there are
+    /// multiple inputs.
+    name: String,
+    /// The extra directory for binaries, if `options.extra_dir` is `None`.
+    extra_dir: Option<PathBuf>,
+    file_mtime: Option<SystemTime>,
+}
+
+fn convert_inputs<R: Read + Seek>(
+    inputs: Vec<Input<R>>,
+    options: ConversionOptions,
+) -> Result<Profile, ConvertError> {
+    let is_merge = inputs.len() > 1;
+    let mut files = Vec::new();
+    for input in inputs {
+        let mut options = options.clone();
+        if options.extra_dir.is_none() {
+            options.extra_dir = input.extra_dir;
+        }
+        let perf_file = PerfFileReader::parse_file(input.reader)?;
+        let info = RecordingInfo::new(&perf_file.perf_file, &options, input.file_mtime)?;
+        files.push((perf_file, info, options, input.name));
+    }
+
+    // The profile starts at the earliest first sample, and each file's samples
+    // are placed relative to it by their wall-clock time.
+    let reference_time = match files.iter().map(|(_, info, ..)| info.start_time).min() {
+        Some(reference_time) => reference_time,
+        None => return Err(ConvertError::NoInputFiles),
+    };
+    // The profile has a single sampling interval, which is the one of the
+    // first file.
+    let interpretation = &files[0].1.interpretation;
+    if options.verbosity >= Verbosity::Normal {
+        for (_, info, _, name) in &files[1..] {
+            if info.interpretation.sampling_is_time_based != interpretation.sampling_is_time_based {
+                eprintln!(
+                    "The main event of {} has a different sampling interval than that of {}; the profile uses the latter",
+                    name, files[0].3
+                );
+            }
+        }
+    }
+    let mut shared = SharedProfile::new(reference_time, interpretation, &options);
+    for (perf_file, info, options, name) in files {
+        let time_offset_ns = match options.align_by_first_sample {
+            true => 0,
+            false => info
+                .start_time
+                .duration_since(reference_time)
+                .map_or(0, |offset| offset.as_nanos() as u64),
+        };
+        let input_name = is_merge.then_some(name.as_str());
+        shared = convert_perf_file_impl(
+            perf_file,
+            info,
+            &options,
+            shared,
+            time_offset_ns,
+            input_name,
+        )?;
+    }
+    Ok(shared.into_profile())
+}
+
+fn convert_perf_file_impl<R: Read>(
+    perf_file: PerfFileReader<R>,
+    info: RecordingInfo,
+    options: &ConversionOptions,
+    shared: SharedProfile,
+    time_offset_ns: u64,
+    input_name: Option<&str>,
+) -> Result<SharedProfile, ConvertError> {
+    match perf_file.perf_file.arch()? {
+        Some("x86_64") => {
+            let cache = framehop::x86_64::CacheX86_64::new();
+            converter::convert::<framehop::x86_64::UnwinderX86_64<Vec<u8>>, ConvertRegsX86_64, _>(
+                perf_file,
+                info,
+                options,
+                cache,
+                shared,
+                time_offset_ns,
+                input_name,
+            )
+        }
+        Some("aarch64") => {
+            let cache = framehop::aarch64::CacheAarch64::new();
+            converter::convert::<framehop::aarch64::UnwinderAarch64<Vec<u8>>, ConvertRegsAarch64, _>(
+                perf_file,
+                info,
+                options,
+                cache,
+                shared,
+                time_offset_ns,
+                input_name,
+            )
+        }
+        Some(other_arch) => {
+            if options.verbosity >= Verbosity::Normal {
+                eprintln!("Unsupported arch {}, unwinding as x86_64", other_arch);
+            }
+            let cache = framehop::x86_64::CacheX86_64::new();
+            converter::convert::<framehop::x86_64::UnwinderX86_64<Vec<u8>>, ConvertRegsX86_64, _>(
+                perf_file,
+                info,
+                options,
+                cache,
+                shared,
+                time_offset_ns,
+                input_name,
+            )
+        }
+        None => Err(ConvertError::UnknownArch),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{convert_file, convert_perf_file, ConversionOptions, ConvertError};
+    use std::io::Cursor;
+    use std::path::Path;
+
+    #[test]
+    fn invalid_input_is_an_error() {
+        let not_perf_data = Cursor::new(b"PERFILE2 is cut off here".to_vec());
+        assert!(matches!(
+            convert_perf_file(not_perf_data, ConversionOptions::default()),
+            Err(ConvertError::Parse(_))
+        ));
+        assert!(matches!(
+            convert_file(
+                Path::new("/nonexistent/perf.data"),
+                ConversionOptions::default()
+            ),
+            Err(ConvertError::Io(_))
+        ));
+    }
+}
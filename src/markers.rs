@@ -0,0 +1,402 @@
+use fxprof_processed_profile::{
+    CategoryHandle, Marker, MarkerFieldFlags, MarkerFieldFormat, MarkerLocations, MarkerTypeHandle,
+    Profile, RuntimeSchemaMarkerField, RuntimeSchemaMarkerSchema, StaticSchemaMarker,
+    StaticSchemaMarkerField, StringHandle,
+};
+
+use std::collections::HashMap;
+
+use crate::tracing_data::{EventFormat, FieldFormat, FieldKind, FieldValue};
+
+/// The marker types which were registered for tracepoints, so that each
+/// schema is only registered once, even if multiple merged files have the
+/// same tracepoints. They're keyed by the schema name and by the names of the
+/// fields, with whether each field is an integer.
+#[derive(Debug, Default)]
+pub struct TracepointMarkerTypeHandles(HashMap<(String, Vec<(String, bool)>), MarkerTypeHandle>);
+
+/// The marker type for the samples of one tracepoint, or of a pair of entry
+/// and exit tracepoints. The schema is only known at runtime, because the
+/// fields come from the tracepoint formats.
+#[derive(Debug, Clone)]
+pub struct TracepointMarkerType {
+    pub marker_type: MarkerTypeHandle,
+    pub name: StringHandle,
+    /// For each of the tracepoints, the fields which are shown in the marker.
+    /// The schema has all these fields, in the same order.
+    fields_per_format: Vec<Vec<FieldFormat>>,
+}
+
+impl TracepointMarkerType {
+    /// Get the marker type with the own fields of all the given formats, and
+    /// register it if it wasn't registered before. If multiple formats have a
+    /// field with the same name, only the first one is used.
+    pub fn register(
+        profile: &mut Profile,
+        handles: &mut TracepointMarkerTypeHandles,
+        name: &str,
+        formats: &[Option<&EventFormat>],
+    ) -> Self {
+        let mut fields_per_format: Vec<Vec<FieldFormat>> = Vec::new();
+        for format in formats {
+            let fields = format
+                .iter()
+                .flat_map(|format| format.own_fields())
+                .filter(|field| {
+                    !fields_per_format
+                        .iter()
+                        .flatten()
+                        .any(|other| other.name == field.name)
+                })
+                .cloned()
+                .collect();
+            fields_per_format.push(fields);
+        }
+        let key = (
+            name.to_string(),
+            fields_per_format
+                .iter()
+                .flatten()
+                .map(|field| (field.name.clone(), field.kind == FieldKind::Integer))
+                .collect(),
+        );
+        let marker_type = *handles
+            .0
+            .entry(key)
+            .or_insert_with(|| Self::register_schema(profile, name, &fields_per_format));
+        Self {
+            marker_type,
+            name: profile.intern_string(name),
+            fields_per_format,
+        }
+    }
+
+    fn register_schema(
+        profile: &mut Profile,
+        name: &str,
+        fields_per_format: &[Vec<FieldFormat>],
+    ) -> MarkerTypeHandle {
+        let fields: Vec<RuntimeSchemaMarkerField> = fields_per_format
+            .iter()
+            .flatten()
+            .map(|field| RuntimeSchemaMarkerField {
+                key: field.name.clone(),
+                label: field.name.clone(),
+                format: match field.kind {
+                    FieldKind::Integer => MarkerFieldFormat::Integer,
+                    _ => MarkerFieldFormat::String,
+                },
+                flags: MarkerFieldFlags::SEARCHABLE,
+            })
+            .collect();
+        // Show all fields in the labels, similar to the output of `perf script`.
+        let field_labels = fields
+            .iter()
+            .map(|field| format!("{}={{marker.data.{}}}", field.key, field.key))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let label = format!("{{marker.name}} {}", field_labels);
+        let schema = RuntimeSchemaMarkerSchema {
+            type_name: name.to_string(),
+            description: None,
+            locations: MarkerLocations::MARKER_CHART | MarkerLocations::MARKER_TABLE,
+            chart_label: Some(field_labels),
+            tooltip_label: Some(label.clone()),
+            table_label: Some(label),
+            fields,
+            graphs: Vec::new(),
+        };
+        profile.register_marker_type(schema)
+    }
+
+    /// Decode the values of the fields which come from the tracepoint at
+    /// `format_index` from a sample's raw data. If the raw data is missing,
+    /// the fields get empty values.
+    pub fn decode_fields(
+        &self,
+        format_index: usize,
+        raw: Option<&[u8]>,
+        big_endian: bool,
+        profile: &mut Profile,
+    ) -> Vec<MarkerFieldValue> {
+        self.fields_per_format[format_index]
+            .iter()
+            .map(|field| {
+                let value = raw.and_then(|raw| field.decode(raw, big_endian));
+                match value {
+                    Some(FieldValue::Signed(value)) => MarkerFieldValue::Number(value as f64),
+                    Some(FieldValue::Unsigned(value)) => MarkerFieldValue::Number(value as f64),
+                    Some(FieldValue::Pointer(address)) => {
+                        MarkerFieldValue::String(profile.intern_string(&format!("{:#x}", address)))
+                    }
+                    Some(FieldValue::String(s)) => {
+                        MarkerFieldValue::String(profile.intern_string(&s))
+                    }
+                    Some(FieldValue::Bytes(bytes)) => {
+                        let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+                        MarkerFieldValue::String(profile.intern_string(&hex))
+                    }
+                    None => match field.kind {
+                        FieldKind::Integer => MarkerFieldValue::Number(0.0),
+                        _ => MarkerFieldValue::String(profile.intern_string("")),
+                    },
+                }
+            })
+            .collect()
+    }
+
+    /// Create a marker with the given field values, which need to be the
+    /// decoded fields of all tracepoints, in order.
+    pub fn make_marker(
+        &self,
+        field_values: Vec<MarkerFieldValue>,
+        category: CategoryHandle,
+    ) -> TracepointMarker {
+        TracepointMarker {
+            marker_type: self.marker_type,
+            name: self.name,
+            category,
+            field_values,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum MarkerFieldValue {
+    String(StringHandle),
+    Number(f64),
+}
+
+/// A marker for a single tracepoint sample, or for the interval between an
+/// entry and an exit tracepoint.
+#[derive(Debug, Clone)]
+pub struct TracepointMarker {
+    marker_type: MarkerTypeHandle,
+    name: StringHandle,
+    category: CategoryHandle,
+    field_values: Vec<MarkerFieldValue>,
+}
+
+impl Marker for TracepointMarker {
+    fn marker_type(&self, _profile: &mut Profile) -> MarkerTypeHandle {
+        self.marker_type
+    }
+
+    fn name(&self, _profile: &mut Profile) -> StringHandle {
+        self.name
+    }
+
+    fn category(&self, _profile: &mut Profile) -> CategoryHandle {
+        self.category
+    }
+
+    fn string_field_value(&self, field_index: u32) -> StringHandle {
+        match self.field_values[field_index as usize] {
+            MarkerFieldValue::String(s) => s,
+            MarkerFieldValue::Number(_) => unreachable!(),
+        }
+    }
+
+    fn number_field_value(&self, field_index: u32) -> f64 {
+        match self.field_values[field_index as usize] {
+            MarkerFieldValue::Number(n) => n,
+            MarkerFieldValue::String(_) => unreachable!(),
+        }
+    }
+}
+
+/// A marker on a CPU track for the time during which a thread was running on
+/// that CPU. The marker's name is the thread's name.
+#[derive(Debug, Clone)]
+pub struct ThreadRunningMarker {
+    pub name: StringHandle,
+    pub category: CategoryHandle,
+    pub pid: i32,
+    pub tid: i32,
+}
+
+impl StaticSchemaMarker for ThreadRunningMarker {
+    const UNIQUE_MARKER_TYPE_NAME: &'static str = "ThreadRunning";
+    const DESCRIPTION: Option<&'static str> = Some("A thread was running on this CPU.");
+    const CHART_LABEL: Option<&'static str> = Some("{marker.name}");
+    const TOOLTIP_LABEL: Option<&'static str> =
+        Some("{marker.name} (pid {marker.data.pid}, tid {marker.data.tid})");
+    const TABLE_LABEL: Option<&'static str> =
+        Some("{marker.name} (pid {marker.data.pid}, tid {marker.data.tid})");
+    const FIELDS: &'static [StaticSchemaMarkerField] = &[
+        StaticSchemaMarkerField {
+            key: "pid",
+            label: "Process ID",
+            format: MarkerFieldFormat::Integer,
+            flags: MarkerFieldFlags::SEARCHABLE,
+        },
+        StaticSchemaMarkerField {
+            key: "tid",
+            label: "Thread ID",
+            format: MarkerFieldFormat::Integer,
+            flags: MarkerFieldFlags::SEARCHABLE,
+        },
+    ];
+
+    fn name(&self, _profile: &mut Profile) -> StringHandle {
+        self.name
+    }
+
+    fn category(&self, _profile: &mut Profile) -> CategoryHandle {
+        self.category
+    }
+
+    fn string_field_value(&self, _field_index: u32) -> StringHandle {
+        unreachable!()
+    }
+
+    fn number_field_value(&self, field_index: u32) -> f64 {
+        match field_index {
+            0 => self.pid.into(),
+            1 => self.tid.into(),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// A marker on a thread for the time between its wakeup and when it started
+/// running on a CPU, if that took longer than the threshold.
+#[derive(Debug, Clone)]
+pub struct RunqueueLatencyMarker {
+    pub category: CategoryHandle,
+}
+
+impl StaticSchemaMarker for RunqueueLatencyMarker {
+    const UNIQUE_MARKER_TYPE_NAME: &'static str = "RunqueueLatency";
+    const DESCRIPTION: Option<&'static str> =
+        Some("The thread was woken up, but had to wait for a CPU before it could run.");
+    const CHART_LABEL: Option<&'static str> = None;
+    const TOOLTIP_LABEL: Option<&'static str> = None;
+    const TABLE_LABEL: Option<&'static str> = None;
+    const FIELDS: &'static [StaticSchemaMarkerField] = &[];
+
+    fn name(&self, profile: &mut Profile) -> StringHandle {
+        profile.intern_string("Runqueue latency")
+    }
+
+    fn category(&self, _profile: &mut Profile) -> CategoryHandle {
+        self.category
+    }
+
+    fn string_field_value(&self, _field_index: u32) -> StringHandle {
+        unreachable!()
+    }
+
+    fn number_field_value(&self, _field_index: u32) -> f64 {
+        unreachable!()
+    }
+}
+
+/// A marker on a thread which woke up a thread of another process, at the
+/// time of the wakeup. The marker's stack is the stack of the waking thread,
+/// which can't go on the woken thread's wakeup marker.
+#[derive(Debug, Clone)]
+pub struct WakingMarker {
+    pub category: CategoryHandle,
+    pub wakee_tid: i32,
+}
+
+impl StaticSchemaMarker for WakingMarker {
+    const UNIQUE_MARKER_TYPE_NAME: &'static str = "Waking";
+    const DESCRIPTION: Option<&'static str> =
+        Some("The thread woke up a thread of another process, e.g. by writing to a pipe.");
+    const CHART_LABEL: Option<&'static str> = Some("{marker.data.wakee_tid}");
+    const TOOLTIP_LABEL: Option<&'static str> = Some("Woke up tid {marker.data.wakee_tid}");
+    const TABLE_LABEL: Option<&'static str> = Some("Woke up tid {marker.data.wakee_tid}");
+    const FIELDS: &'static [StaticSchemaMarkerField] = &[StaticSchemaMarkerField {
+        key: "wakee_tid",
+        label: "Woken thread ID",
+        format: MarkerFieldFormat::Integer,
+        flags: MarkerFieldFlags::SEARCHABLE,
+    }];
+
+    fn name(&self, profile: &mut Profile) -> StringHandle {
+        profile.intern_string("Waking")
+    }
+
+    fn category(&self, _profile: &mut Profile) -> CategoryHandle {
+        self.category
+    }
+
+    fn string_field_value(&self, _field_index: u32) -> StringHandle {
+        unreachable!()
+    }
+
+    fn number_field_value(&self, field_index: u32) -> f64 {
+        match field_index {
+            0 => self.wakee_tid.into(),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// A marker on a thread which was woken up by another thread, at the time of
+/// the wakeup. The marker's stack is the stack of the waking thread.
+#[derive(Debug, Clone)]
+pub struct WakeupMarker {
+    pub waker_name: StringHandle,
+    pub category: CategoryHandle,
+    pub waker_pid: i32,
+    pub waker_tid: i32,
+}
+
+impl StaticSchemaMarker for WakeupMarker {
+    const UNIQUE_MARKER_TYPE_NAME: &'static str = "Wakeup";
+    const DESCRIPTION: Option<&'static str> =
+        Some("The thread was woken up by another thread, e.g. by releasing a lock.");
+    const CHART_LABEL: Option<&'static str> = Some("{marker.data.waker}");
+    const TOOLTIP_LABEL: Option<&'static str> = Some(
+        "Woken by {marker.data.waker} (pid {marker.data.waker_pid}, tid {marker.data.waker_tid})",
+    );
+    const TABLE_LABEL: Option<&'static str> = Some(
+        "Woken by {marker.data.waker} (pid {marker.data.waker_pid}, tid {marker.data.waker_tid})",
+    );
+    const FIELDS: &'static [StaticSchemaMarkerField] = &[
+        StaticSchemaMarkerField {
+            key: "waker",
+            label: "Waker",
+            format: MarkerFieldFormat::String,
+            flags: MarkerFieldFlags::SEARCHABLE,
+        },
+        StaticSchemaMarkerField {
+            key: "waker_pid",
+            label: "Waker process ID",
+            format: MarkerFieldFormat::Integer,
+            flags: MarkerFieldFlags::SEARCHABLE,
+        },
+        StaticSchemaMarkerField {
+            key: "waker_tid",
+            label: "Waker thread ID",
+            format: MarkerFieldFormat::Integer,
+            flags: MarkerFieldFlags::SEARCHABLE,
+        },
+    ];
+
+    fn name(&self, profile: &mut Profile) -> StringHandle {
+        profile.intern_string("Wakeup")
+    }
+
+    fn category(&self, _profile: &mut Profile) -> CategoryHandle {
+        self.category
+    }
+
+    fn string_field_value(&self, field_index: u32) -> StringHandle {
+        match field_index {
+            0 => self.waker_name,
+            _ => unreachable!(),
+        }
+    }
+
+    fn number_field_value(&self, field_index: u32) -> f64 {
+        match field_index {
+            1 => self.waker_pid.into(),
+            2 => self.waker_tid.into(),
+            _ => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use std::path::PathBuf;
+
+/// Controls how much the converter prints to stderr during the conversion.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Only print errors.
+    Quiet,
+    /// Also print warnings, e.g. about binaries with mismatching build IDs.
+    #[default]
+    Normal,
+    /// Also print diagnostic information, e.g. about binaries that couldn't be found.
+    Verbose,
+}
+
+/// The settings which influence the conversion.
+///
+/// More options may be added in the future. To stay compatible, create the
+/// options with `ConversionOptions::default()` and then assign the fields you
+/// want to change.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct ConversionOptions {
+    /// An extra directory to look for binaries in, if they can't be found at
+    /// the path from the mmap record.
+    pub extra_dir: Option<PathBuf>,
+    /// A directory with a copy of the recording machine's root filesystem,
+    /// e.g. an unpacked container image. If set, the recorded paths of the
+    /// binaries are looked up relative to it instead of to `/`, like with
+    /// `perf report --symfs`.
+    pub sysroot: Option<PathBuf>,
+    /// The root filesystems of containers, e.g. the merged directory of an
+    /// overlay filesystem. The binaries of the selected processes are looked up
+    /// relative to these directories. The root of a running process is found
+    /// through `/proc/<pid>/root` without this, if the converter may access it,
+    /// so this is mostly needed for processes which have exited since the
+    /// recording.
+    pub container_roots: Vec<(ContainerSelector, PathBuf)>,
+    /// The root of perf's build ID cache, in which `perf record` and
+    /// `perf buildid-cache` store copies of the recorded binaries. Binaries
+    /// are looked up in it by their build ID. Defaults to `$PERF_BUILDID_DIR`
+    /// or `~/.debug`.
+    pub build_id_cache_dir: Option<PathBuf>,
+    /// A directory laid out like a debuginfod client cache, i.e. with
+    /// `<build id>/executable` and `<build id>/debuginfo` files. Binaries and
+    /// their separate debug files are looked up in it by their build ID.
+    /// Defaults to `$DEBUGINFOD_CACHE_PATH` or `~/.cache/debuginfod_client`.
+    pub debuginfod_cache_dir: Option<PathBuf>,
+    /// Overrides the off-CPU sampling interval which is otherwise derived from
+    /// the main event.
+    pub off_cpu_sampling_interval_ns: Option<u64>,
+    /// The name of the event whose samples make up the main thread tracks, e.g.
+    /// "cycles". Defaults to the first sampled event which is neither a
+    /// tracepoint nor the dummy event.
+    pub main_event: Option<String>,
+    pub verbosity: Verbosity,
+    /// Whether to resolve addresses to function names during the conversion,
+    /// using the symbol tables and DWARF debug info of the binaries, so that
+    /// the profile doesn't need to be symbolicated afterwards.
+    pub symbolicate: bool,
+    /// Whether to add a track for each CPU, with the samples taken on that CPU
+    /// and markers for the threads which ran on it. This needs a recording
+    /// with `perf record --sample-cpu`.
+    pub cpu_tracks: bool,
+    /// The runqueue latency above which a thread gets a marker for the time
+    /// between its wakeup and when it started running. Defaults to 1ms. This
+    /// needs a recording with `sched:sched_waking` or `sched:sched_wakeup` and
+    /// with context switch events.
+    pub runqueue_latency_threshold_ns: Option<u64>,
+    /// Whether to weight the samples on the thread tracks by the time they
+    /// stand for, in milliseconds, instead of counting them. On-CPU samples
+    /// are weighted by the CPU time since the previous sample, and off-CPU
+    /// samples by the off-CPU time, so that the call tree shows wall-clock time
+    /// even if the main event isn't time-based, e.g. for `cycles`.
+    pub weight_samples_by_time: bool,
+    /// Only convert the samples and context switches at or after this time.
+    /// Mmap, comm, fork and exit records are used regardless of their time, so
+    /// that libraries and thread names are still known.
+    pub time_range_start: Option<TimeRangeBound>,
+    /// Only convert the samples and context switches before this time.
+    pub time_range_end: Option<TimeRangeBound>,
+    /// Only convert the threads of these processes. Empty means all processes.
+    pub pid_filter: Vec<i32>,
+    /// Only convert these threads. Empty means all threads.
+    pub tid_filter: Vec<i32>,
+    /// Only convert the threads whose name, or whose process's name, matches
+    /// this regular expression.
+    pub comm_filter: Option<String>,
+    /// When converting multiple files, start each file's samples at the
+    /// start of the profile, instead of placing them at their wall-clock time.
+    /// This is useful to compare runs which were recorded one after another.
+    pub align_by_first_sample: bool,
+}
+
+/// Selects the processes which ran in a container.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContainerSelector {
+    /// The process with this pid.
+    Pid(i32),
+    /// The processes in this cgroup or in the cgroups below it, e.g.
+    /// `/system.slice/docker-0123abcd.scope`. The cgroup of a process is read
+    /// from `/proc/<pid>/cgroup`, so this only works for running processes.
+    Cgroup(String),
+}
+
+/// The start or the end of the time range to convert.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeRangeBound {
+    /// Nanoseconds after the first sample in the file.
+    SinceFirstSample(u64),
+    /// A perf timestamp in nanoseconds, i.e. on the clock of the recording.
+    /// `perf script` shows these timestamps in seconds.
+    Absolute(u64),
+}
+
+impl TimeRangeBound {
+    /// Returns the perf timestamp of this bound.
+    pub fn resolve(&self, first_sample_time: u64) -> u64 {
+        match *self {
+            TimeRangeBound::SinceFirstSample(ns) => first_sample_time.saturating_add(ns),
+            TimeRangeBound::Absolute(timestamp) => timestamp,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TimeRangeBound;
+
+    #[test]
+    fn resolve_time_range_bound() {
+        assert_eq!(TimeRangeBound::SinceFirstSample(500).resolve(1000), 1500);
+        assert_eq!(
+            TimeRangeBound::SinceFirstSample(u64::MAX).resolve(1000),
+            u64::MAX
+        );
+        assert_eq!(TimeRangeBound::Absolute(500).resolve(1000), 500);
+    }
+}
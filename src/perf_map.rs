@@ -0,0 +1,172 @@
+use debugid::DebugId;
+use fxprof_processed_profile::{LibraryInfo, Symbol, SymbolTable};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Entries whose addresses are less than a page apart are put into the same
+/// mapping. No other mapping can fit into such a gap, so the JIT mappings
+/// can't hide the mappings of regular libraries.
+const MAX_GAP_IN_MAPPING: u64 = 4096;
+
+/// One line of a perf map file: `<start> <size> <name>`, with the start
+/// address and the size in hex.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PerfMapEntry {
+    pub start: u64,
+    pub size: u64,
+    pub name: String,
+}
+
+/// The JIT code described by a perf map file, as a synthetic library.
+///
+/// JIT code can be spread out over the whole address space, so the library
+/// has one mapping per cluster of nearby functions, and the relative
+/// addresses of the clusters are packed next to each other.
+#[derive(Debug, Clone)]
+pub struct PerfMapLib {
+    pub lib: LibraryInfo,
+    /// The mappings as (start AVMA, end AVMA, relative address at start).
+    pub mappings: Vec<(u64, u64, u32)>,
+}
+
+/// Look for `perf-<pid>.map` in the given directories.
+pub fn find_perf_map_file(pid: i32, dirs: &[PathBuf]) -> Option<PathBuf> {
+    let filename = format!("perf-{}.map", pid);
+    dirs.iter()
+        .map(|dir| dir.join(&filename))
+        .find(|path| path.is_file())
+}
+
+/// Parse the contents of a perf map file, such as the ones written by the
+/// JVM's perf-map-agent, by Node with `--perf-basic-prof` or by .NET with
+/// `DOTNET_PerfMapEnabled=1`. Malformed lines are skipped.
+pub fn parse_perf_map(contents: &str) -> Vec<PerfMapEntry> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.trim().splitn(3, ' ');
+            let start = parse_hex(parts.next()?)?;
+            let size = parse_hex(parts.next()?)?;
+            let name = parts.next()?.trim().to_string();
+            Some(PerfMapEntry { start, size, name })
+        })
+        .collect()
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    u64::from_str_radix(s, 16).ok()
+}
+
+impl PerfMapLib {
+    pub fn new(path: &Path, entries: Vec<PerfMapEntry>) -> Self {
+        // JIT code can be replaced at the same address; the last entry wins.
+        let entries: BTreeMap<u64, PerfMapEntry> = entries
+            .into_iter()
+            .map(|entry| (entry.start, entry))
+            .collect();
+
+        let mut mappings: Vec<(u64, u64, u32)> = Vec::new();
+        let mut symbols = Vec::new();
+        for entry in entries.into_values() {
+            let end = entry.start.saturating_add(entry.size);
+            match mappings.last_mut() {
+                Some((_, mapping_end, _))
+                    if entry.start < mapping_end.saturating_add(MAX_GAP_IN_MAPPING) =>
+                {
+                    *mapping_end = (*mapping_end).max(end);
+                }
+                last_mapping => {
+                    let relative_address_at_start = match last_mapping {
+                        Some((start, end, relative_address_at_start)) => {
+                            u64::from(*relative_address_at_start) + (*end - *start)
+                        }
+                        None => 0,
+                    };
+                    // The relative addresses need to fit into 32 bits.
+                    let relative_address_at_start = match u32::try_from(relative_address_at_start) {
+                        Ok(relative_address_at_start) => relative_address_at_start,
+                        Err(_) => break,
+                    };
+                    mappings.push((entry.start, end, relative_address_at_start));
+                }
+            }
+            let (mapping_start, _, relative_address_at_start) = *mappings.last().unwrap();
+            let address = match u32::try_from(
+                u64::from(relative_address_at_start) + (entry.start - mapping_start),
+            ) {
+                Ok(address) => address,
+                Err(_) => break,
+            };
+            symbols.push(Symbol {
+                address,
+                size: u32::try_from(entry.size).ok().filter(|size| *size != 0),
+                name: entry.name,
+            });
+        }
+
+        let name = path
+            .file_name()
+            .map_or("<perf map>".into(), |f| f.to_string_lossy().to_string());
+        let path = path.to_string_lossy().to_string();
+        let lib = LibraryInfo {
+            name: name.clone(),
+            debug_name: name,
+            path: path.clone(),
+            debug_path: path,
+            debug_id: DebugId::nil(),
+            code_id: None,
+            arch: None,
+            symbol_table: Some(Arc::new(SymbolTable::new(symbols))),
+        };
+        Self { lib, mappings }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_perf_map, PerfMapEntry, PerfMapLib};
+    use std::path::Path;
+
+    #[test]
+    fn parse_and_cluster_perf_map() {
+        let entries = parse_perf_map(
+            "7f0000001000 40 LazyCompile:~foo /app/main.js:1\n\
+             0x7f0000001040 0x20 Interpreter\n\
+             garbage\n\
+             7f0000100000 10 void Foo::Bar(int)\n\
+             7f0000001000 30 LazyCompile:*foo /app/main.js:1\n",
+        );
+        assert_eq!(entries.len(), 4);
+        assert_eq!(
+            entries[1],
+            PerfMapEntry {
+                start: 0x7f0000001040,
+                size: 0x20,
+                name: "Interpreter".to_string()
+            }
+        );
+
+        let perf_map = PerfMapLib::new(Path::new("/tmp/perf-123.map"), entries);
+        assert_eq!(perf_map.lib.name, "perf-123.map");
+        assert_eq!(
+            perf_map.mappings,
+            vec![
+                (0x7f0000001000, 0x7f0000001060, 0),
+                (0x7f0000100000, 0x7f0000100010, 0x60),
+            ]
+        );
+        let symbol_table = perf_map.lib.symbol_table.unwrap();
+        assert_eq!(
+            symbol_table.lookup(0x10).unwrap().name,
+            "LazyCompile:*foo /app/main.js:1"
+        );
+        assert_eq!(symbol_table.lookup(0x45).unwrap().name, "Interpreter");
+        assert_eq!(
+            symbol_table.lookup(0x65).unwrap().name,
+            "void Foo::Bar(int)"
+        );
+        assert!(symbol_table.lookup(0x35).is_none());
+    }
+}
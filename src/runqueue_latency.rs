@@ -0,0 +1,167 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+/// The width of the bars in the printed histograms.
+const HISTOGRAM_BAR_WIDTH: u64 = 40;
+
+/// Measures the runqueue latency of threads, i.e. the time between a thread
+/// being woken up and the thread actually running on a CPU. During this time
+/// the thread is runnable, but it's waiting for a CPU.
+///
+/// Wakeups come from `sched:sched_waking` or `sched:sched_wakeup` samples, and
+/// switch-ins from context switch records.
+pub struct RunqueueLatencies {
+    threshold_ns: u64,
+    /// The wakeup timestamps of the threads which haven't run since, keyed by tid.
+    pending_wakeups: HashMap<i32, u64>,
+    /// The latency histograms, keyed by pid.
+    histograms: BTreeMap<i32, LatencyHistogram>,
+    process_names: HashMap<i32, String>,
+}
+
+impl RunqueueLatencies {
+    pub fn new(threshold_ns: u64) -> Self {
+        Self {
+            threshold_ns,
+            pending_wakeups: HashMap::new(),
+            histograms: BTreeMap::new(),
+            process_names: HashMap::new(),
+        }
+    }
+
+    /// A thread was woken up. If it's woken up multiple times before it runs,
+    /// the first wakeup counts, because that's when it became runnable.
+    pub fn handle_wakeup(&mut self, tid: i32, timestamp: u64) {
+        self.pending_wakeups.entry(tid).or_insert(timestamp);
+    }
+
+    /// A thread was switched out. A wakeup which arrived while the thread was
+    /// still running didn't make the thread wait for anything.
+    pub fn handle_switch_out(&mut self, tid: i32) {
+        self.pending_wakeups.remove(&tid);
+    }
+
+    /// A thread was switched in. If it was woken up before, its latency is
+    /// added to the histogram of its process, and if the latency is at least
+    /// the threshold, the wakeup timestamp is returned.
+    pub fn handle_switch_in(&mut self, pid: i32, tid: i32, timestamp: u64) -> Option<u64> {
+        let wakeup_timestamp = self.pending_wakeups.remove(&tid)?;
+        let latency_ns = timestamp.saturating_sub(wakeup_timestamp);
+        self.histograms.entry(pid).or_default().add(latency_ns);
+        if latency_ns >= self.threshold_ns {
+            Some(wakeup_timestamp)
+        } else {
+            None
+        }
+    }
+
+    pub fn set_process_name(&mut self, pid: i32, name: &str) {
+        self.process_names.insert(pid, name.to_string());
+    }
+
+    /// Print the histogram of each process to stderr.
+    pub fn report(&self) {
+        for (pid, histogram) in &self.histograms {
+            let name = self
+                .process_names
+                .get(pid)
+                .map_or("<unknown>", String::as_str);
+            eprintln!(
+                "Runqueue latency of {} (pid {}): {} wakeups, average {}us, max {}us",
+                name,
+                pid,
+                histogram.count,
+                histogram.total_ns / histogram.count.max(1) / 1000,
+                histogram.max_ns / 1000
+            );
+            eprint!("{}", histogram.format());
+        }
+    }
+}
+
+/// A histogram with power-of-two buckets in microseconds, like the ones which
+/// are printed by bcc's `runqlat`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// Bucket 0 is for 0us, and bucket n is for 2^(n-1)us to 2^n - 1us.
+    bucket_counts: Vec<u64>,
+    count: u64,
+    total_ns: u64,
+    max_ns: u64,
+}
+
+impl LatencyHistogram {
+    pub fn add(&mut self, latency_ns: u64) {
+        let latency_us = latency_ns / 1000;
+        let bucket = (u64::BITS - latency_us.leading_zeros()) as usize;
+        if self.bucket_counts.len() <= bucket {
+            self.bucket_counts.resize(bucket + 1, 0);
+        }
+        self.bucket_counts[bucket] += 1;
+        self.count += 1;
+        self.total_ns += latency_ns;
+        self.max_ns = self.max_ns.max(latency_ns);
+    }
+
+    fn format(&self) -> String {
+        let max_count = self.bucket_counts.iter().copied().max().unwrap_or(0).max(1);
+        let mut s = String::new();
+        let _ = writeln!(s, "{:>20} : {:<8} distribution", "usecs", "count");
+        for (bucket, count) in self.bucket_counts.iter().enumerate() {
+            let (low, high) = match bucket {
+                0 => (0, 0),
+                _ => (1u64 << (bucket - 1), (1u64 << bucket) - 1),
+            };
+            let bar_len = (count * HISTOGRAM_BAR_WIDTH / max_count) as usize;
+            let _ = writeln!(
+                s,
+                "{:>9} -> {:<9} : {:<8} |{:<width$}|",
+                low,
+                high,
+                count,
+                "*".repeat(bar_len),
+                width = HISTOGRAM_BAR_WIDTH as usize
+            );
+        }
+        s
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LatencyHistogram, RunqueueLatencies};
+
+    #[test]
+    fn latencies_from_wakeup_to_switch_in() {
+        let mut latencies = RunqueueLatencies::new(1_000_000);
+        latencies.handle_wakeup(11, 1_000_000);
+        // A second wakeup doesn't reset the wakeup time.
+        latencies.handle_wakeup(11, 1_200_000);
+        assert_eq!(latencies.handle_switch_in(10, 11, 1_300_000), None);
+        latencies.handle_wakeup(11, 2_000_000);
+        assert_eq!(
+            latencies.handle_switch_in(10, 11, 3_500_000),
+            Some(2_000_000)
+        );
+        // A wakeup of a running thread doesn't count.
+        latencies.handle_wakeup(11, 4_000_000);
+        latencies.handle_switch_out(11);
+        assert_eq!(latencies.handle_switch_in(10, 11, 5_000_000), None);
+        // A switch-in without a wakeup, e.g. after preemption, doesn't count.
+        assert_eq!(latencies.handle_switch_in(10, 12, 5_000_000), None);
+
+        let mut expected = LatencyHistogram::default();
+        expected.add(300_000);
+        expected.add(1_500_000);
+        assert_eq!(latencies.histograms[&10], expected);
+        assert_eq!(latencies.histograms.len(), 1);
+        assert_eq!(
+            expected.format().lines().collect::<Vec<_>>()[10..],
+            [
+                "      256 -> 511       : 1        |****************************************|",
+                "      512 -> 1023      : 0        |                                        |",
+                "     1024 -> 2047      : 1        |****************************************|",
+            ]
+        );
+    }
+}
@@ -0,0 +1,209 @@
+use fxprof_processed_profile::{
+    CategoryColor, CategoryPairHandle, Frame, FrameFlags, FrameInfo, LibMappings, LibraryHandle,
+    ProcessHandle, Profile,
+};
+use linux_perf_data::linux_perf_event_reader::constants::{
+    PERF_CONTEXT_GUEST, PERF_CONTEXT_GUEST_KERNEL, PERF_CONTEXT_GUEST_USER, PERF_CONTEXT_KERNEL,
+    PERF_CONTEXT_USER,
+};
+use linux_perf_data::linux_perf_event_reader::CpuMode;
+
+use crate::context_switch::OffCpuReason;
+
+#[derive(Debug, Clone, Copy)]
+pub struct StackConverter {
+    pub user_category: CategoryPairHandle,
+    pub kernel_category: CategoryPairHandle,
+}
+
+impl StackConverter {
+    pub fn convert_stack(&self, stack: Vec<StackFrame>) -> impl Iterator<Item = FrameInfo> {
+        let user_category = self.user_category;
+        let kernel_category = self.kernel_category;
+        stack.into_iter().rev().filter_map(move |frame| {
+            let (location, mode) = match frame {
+                StackFrame::InstructionPointer(addr, mode) => {
+                    (Frame::InstructionPointer(addr), mode)
+                }
+                StackFrame::ReturnAddress(addr, mode) => (Frame::ReturnAddress(addr), mode),
+                StackFrame::TruncatedStackMarker => return None,
+            };
+            let category_pair = match mode {
+                StackMode::User => user_category,
+                StackMode::Kernel => kernel_category,
+            };
+            Some(FrameInfo {
+                frame: location,
+                category_pair,
+                flags: FrameFlags::empty(),
+            })
+        })
+    }
+
+    pub fn convert_stack_no_kernel<'a>(
+        &self,
+        stack: &'a [StackFrame],
+    ) -> impl Iterator<Item = FrameInfo> + 'a {
+        let user_category = self.user_category;
+        stack.iter().rev().filter_map(move |frame| {
+            let (location, mode) = match *frame {
+                StackFrame::InstructionPointer(addr, mode) => {
+                    (Frame::InstructionPointer(addr), mode)
+                }
+                StackFrame::ReturnAddress(addr, mode) => (Frame::ReturnAddress(addr), mode),
+                StackFrame::TruncatedStackMarker => return None,
+            };
+            match mode {
+                StackMode::User => Some(FrameInfo {
+                    frame: location,
+                    category_pair: user_category,
+                    flags: FrameFlags::empty(),
+                }),
+                StackMode::Kernel => None,
+            }
+        })
+    }
+}
+
+/// The library mappings of a process. They are added to the profile and kept
+/// here as well: the profile resolves the addresses of a thread's frames
+/// through the mappings of the thread's own process, so frames which go on the
+/// threads of another process, i.e. the CPU tracks, need to be resolved to
+/// library-relative addresses beforehand.
+#[derive(Debug)]
+pub struct ProcessLibMappings {
+    process: ProcessHandle,
+    mappings: LibMappings<LibraryHandle>,
+}
+
+impl ProcessLibMappings {
+    pub fn new(process: ProcessHandle) -> Self {
+        Self {
+            process,
+            mappings: LibMappings::new(),
+        }
+    }
+
+    pub fn add(
+        &mut self,
+        lib: LibraryHandle,
+        start_avma: u64,
+        end_avma: u64,
+        relative_address_at_start: u32,
+        profile: &mut Profile,
+    ) {
+        profile.add_lib_mapping(
+            self.process,
+            lib,
+            start_avma,
+            end_avma,
+            relative_address_at_start,
+        );
+        self.mappings
+            .add_mapping(start_avma, end_avma, relative_address_at_start, lib);
+    }
+
+    /// Turn the frame's address into an address relative to the library which
+    /// is mapped there. Addresses outside of the process's mappings, e.g.
+    /// kernel addresses, are left alone.
+    pub fn resolve_frame(&self, frame_info: FrameInfo) -> FrameInfo {
+        let frame = match frame_info.frame {
+            Frame::InstructionPointer(ip) => match self.mappings.convert_address(ip) {
+                Some((address, lib)) => Frame::RelativeAddressFromInstructionPointer(*lib, address),
+                None => frame_info.frame,
+            },
+            // Look up the call instruction, which may be the last one of the
+            // mapping.
+            Frame::ReturnAddress(ra) => match self.mappings.convert_address(ra.saturating_sub(1)) {
+                Some((address, lib)) => {
+                    Frame::RelativeAddressFromAdjustedReturnAddress(*lib, address)
+                }
+                None => frame_info.frame,
+            },
+            Frame::AdjustedReturnAddress(ara) => match self.mappings.convert_address(ara) {
+                Some((address, lib)) => {
+                    Frame::RelativeAddressFromAdjustedReturnAddress(*lib, address)
+                }
+                None => frame_info.frame,
+            },
+            frame => frame,
+        };
+        FrameInfo {
+            frame,
+            ..frame_info
+        }
+    }
+}
+
+/// The root frames of off-cpu samples, which put the time during which a thread
+/// was preempted and the time during which it was blocked into separate subtrees.
+#[derive(Debug, Clone)]
+pub struct OffCpuRootFrames {
+    preempted: FrameInfo,
+    blocked: FrameInfo,
+}
+
+impl OffCpuRootFrames {
+    pub fn new(profile: &mut Profile) -> Self {
+        let mut make_frame = |name: &str, color: CategoryColor| FrameInfo {
+            frame: Frame::Label(profile.intern_string(name)),
+            category_pair: profile.add_category(name, color).into(),
+            flags: FrameFlags::empty(),
+        };
+        Self {
+            preempted: make_frame("Preempted", CategoryColor::Red),
+            blocked: make_frame("Blocked", CategoryColor::Gray),
+        }
+    }
+
+    /// The frames of an off-cpu sample: the root frame for the reason, followed
+    /// by the thread's stack at the time it was switched out.
+    pub fn stack<'a>(
+        &self,
+        reason: OffCpuReason,
+        off_cpu_stack: &'a [FrameInfo],
+    ) -> impl Iterator<Item = FrameInfo> + 'a {
+        let root_frame = match reason {
+            OffCpuReason::Preempted => self.preempted.clone(),
+            OffCpuReason::Blocked => self.blocked.clone(),
+        };
+        std::iter::once(root_frame).chain(off_cpu_stack.iter().cloned())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum StackFrame {
+    InstructionPointer(u64, StackMode),
+    ReturnAddress(u64, StackMode),
+    TruncatedStackMarker,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum StackMode {
+    User,
+    Kernel,
+}
+
+impl StackMode {
+    /// Detect stack mode from a "context frame".
+    ///
+    /// Context frames are present in sample callchains; they're u64 addresses
+    /// which are `>= PERF_CONTEXT_MAX`.
+    pub fn from_context_frame(frame: u64) -> Option<Self> {
+        match frame {
+            PERF_CONTEXT_KERNEL | PERF_CONTEXT_GUEST_KERNEL => Some(Self::Kernel),
+            PERF_CONTEXT_USER | PERF_CONTEXT_GUEST | PERF_CONTEXT_GUEST_USER => Some(Self::User),
+            _ => None,
+        }
+    }
+}
+
+impl From<CpuMode> for StackMode {
+    /// Convert CpuMode into StackMode.
+    fn from(cpu_mode: CpuMode) -> Self {
+        match cpu_mode {
+            CpuMode::Kernel | CpuMode::GuestKernel => Self::Kernel,
+            _ => Self::User,
+        }
+    }
+}
@@ -0,0 +1,319 @@
+use fxprof_processed_profile::{
+    Frame, FrameFlags, FrameInfo, Profile, StringHandle, Symbol, SymbolTable,
+};
+use gimli::{EndianRcSlice, RunTimeEndian};
+use object::{Object, ObjectSection, ObjectSymbol, SymbolKind};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// The symbol information of one binary, for resolving addresses during the
+/// conversion.
+///
+/// Function names come from the symbol table, which is given to the profile
+/// as the library's symbol table. If the binary has DWARF debug info, it is
+/// used to find the functions which were inlined at an address.
+pub struct LibSymbols {
+    pub symbol_table: Arc<SymbolTable>,
+    dwarf: Option<addr2line::Context<EndianRcSlice<RunTimeEndian>>>,
+}
+
+impl LibSymbols {
+    pub fn from_object(file: &object::File) -> Self {
+        let mut symbols: Vec<Symbol> = file
+            .symbols()
+            .chain(file.dynamic_symbols())
+            .filter(|symbol| symbol.kind() == SymbolKind::Text && symbol.address() != 0)
+            .filter_map(|symbol| {
+                let address = u32::try_from(symbol.address()).ok()?;
+                let name = symbol.name().ok().filter(|name| !name.is_empty())?;
+                Some(Symbol {
+                    address,
+                    size: u32::try_from(symbol.size()).ok().filter(|size| *size != 0),
+                    name: addr2line::demangle_auto(Cow::Borrowed(name), None).into_owned(),
+                })
+            })
+            .collect();
+        // Functions are usually in both .symtab and .dynsym.
+        symbols.sort_by_key(|symbol| symbol.address);
+        symbols.dedup_by_key(|symbol| symbol.address);
+
+        let dwarf = match file.section_by_name(".debug_info") {
+            Some(_) => load_dwarf(file),
+            None => None,
+        };
+        Self {
+            symbol_table: Arc::new(SymbolTable::new(symbols)),
+            dwarf,
+        }
+    }
+
+    /// Returns the names of the functions which were inlined at this address,
+    /// from the outermost to the innermost. The function which contains the
+    /// address in the binary is not included; its name is in the symbol table.
+    fn inlined_functions(&self, svma: u64) -> Vec<String> {
+        let dwarf = match &self.dwarf {
+            Some(dwarf) => dwarf,
+            None => return Vec::new(),
+        };
+        let mut frames = match dwarf.find_frames(svma) {
+            Ok(frames) => frames,
+            Err(_) => return Vec::new(),
+        };
+        // The frames are ordered from the innermost inlined function to the
+        // outer function.
+        let mut names = Vec::new();
+        while let Ok(Some(frame)) = frames.next() {
+            let name = frame
+                .function
+                .as_ref()
+                .and_then(|function| function.demangle().ok())
+                .map_or_else(|| "<unknown inlined function>".to_string(), Cow::into_owned);
+            names.push(name);
+        }
+        names.pop();
+        names.reverse();
+        names
+    }
+}
+
+fn load_dwarf(file: &object::File) -> Option<addr2line::Context<EndianRcSlice<RunTimeEndian>>> {
+    let endian = if file.is_little_endian() {
+        RunTimeEndian::Little
+    } else {
+        RunTimeEndian::Big
+    };
+    let load_section = |id: gimli::SectionId| -> Result<_, gimli::Error> {
+        let data = file
+            .section_by_name(id.name())
+            .and_then(|section| section.uncompressed_data().ok())
+            .unwrap_or(Cow::Borrowed(&[]));
+        Ok(EndianRcSlice::new(Rc::from(&*data), endian))
+    };
+    let dwarf = gimli::Dwarf::load(load_section).ok()?;
+    addr2line::Context::from_dwarf(dwarf).ok()
+}
+
+struct SymbolicatedMapping {
+    avma_range: Range<u64>,
+    base_avma: u64,
+    symbols: Rc<LibSymbols>,
+}
+
+/// The symbol information for the mappings of one process.
+#[derive(Default)]
+pub struct ProcessSymbols {
+    mappings: Vec<SymbolicatedMapping>,
+    /// The labels of the inlined functions at a lookup address, outermost first.
+    inline_frames_cache: HashMap<u64, Vec<StringHandle>>,
+}
+
+impl ProcessSymbols {
+    pub fn add_mapping(&mut self, avma_range: Range<u64>, base_avma: u64, symbols: Rc<LibSymbols>) {
+        // A new mapping replaces any old mappings at the same addresses.
+        self.mappings.retain(|mapping| {
+            mapping.avma_range.end <= avma_range.start || avma_range.end <= mapping.avma_range.start
+        });
+        self.inline_frames_cache.clear();
+        self.mappings.push(SymbolicatedMapping {
+            avma_range,
+            base_avma,
+            symbols,
+        });
+    }
+
+    /// Add a label frame for each inlined function after the frame whose
+    /// address it was inlined at, so that inlined functions show up in the
+    /// call tree as if they were called normally.
+    ///
+    /// `frames` needs to be ordered from the root to the leaf.
+    pub fn add_inline_frames(
+        &mut self,
+        frames: impl Iterator<Item = FrameInfo>,
+        profile: &mut Profile,
+    ) -> Vec<FrameInfo> {
+        if self.mappings.is_empty() {
+            return frames.collect();
+        }
+        let mut result = Vec::new();
+        for frame_info in frames {
+            // Look up return addresses at the call instruction, which is
+            // before the return address.
+            let lookup_address = match frame_info.frame {
+                Frame::InstructionPointer(address) => Some(address),
+                Frame::ReturnAddress(address) => Some(address.saturating_sub(1)),
+                _ => None,
+            };
+            let category_pair = frame_info.category_pair;
+            result.push(frame_info);
+            let lookup_address = match lookup_address {
+                Some(lookup_address) => lookup_address,
+                None => continue,
+            };
+            let mappings = &self.mappings;
+            let labels = self
+                .inline_frames_cache
+                .entry(lookup_address)
+                .or_insert_with(|| {
+                    match mappings
+                        .iter()
+                        .find(|mapping| mapping.avma_range.contains(&lookup_address))
+                    {
+                        Some(mapping) => mapping
+                            .symbols
+                            .inlined_functions(lookup_address - mapping.base_avma)
+                            .iter()
+                            .map(|name| profile.intern_string(name))
+                            .collect(),
+                        None => Vec::new(),
+                    }
+                });
+            result.extend(labels.iter().map(|label| FrameInfo {
+                frame: Frame::Label(*label),
+                category_pair,
+                flags: FrameFlags::empty(),
+            }));
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LibSymbols, ProcessSymbols};
+    use fxprof_processed_profile::{
+        CategoryColor, Frame, FrameFlags, FrameInfo, Profile, ReferenceTimestamp, SamplingInterval,
+    };
+    use gimli::write::{Address, AttributeValue, DwarfUnit, EndianVec, Sections};
+    use object::write::{Object, StandardSection, Symbol, SymbolSection};
+    use object::{
+        Architecture, BinaryFormat, Endianness, SectionKind, SymbolFlags, SymbolKind, SymbolScope,
+    };
+    use std::rc::Rc;
+
+    /// An object file with the function `outer` at 0x100..0x180, into which
+    /// `inner` was inlined at 0x110..0x120, and with the DWARF info for this.
+    fn object_with_inlined_function() -> Vec<u8> {
+        let mut obj = Object::new(BinaryFormat::Elf, Architecture::X86_64, Endianness::Little);
+        let text = obj.section_id(StandardSection::Text);
+        obj.append_section_data(text, &[0x90; 0x200], 16);
+        obj.add_symbol(Symbol {
+            name: b"outer".to_vec(),
+            value: 0x100,
+            size: 0x80,
+            kind: SymbolKind::Text,
+            scope: SymbolScope::Linkage,
+            weak: false,
+            section: SymbolSection::Section(text),
+            flags: SymbolFlags::None,
+        });
+
+        let encoding = gimli::Encoding {
+            format: gimli::Format::Dwarf32,
+            version: 4,
+            address_size: 8,
+        };
+        let mut dwarf = DwarfUnit::new(encoding);
+        let root = dwarf.unit.root();
+        let set_range = |dwarf: &mut DwarfUnit, id, start, len| {
+            let entry = dwarf.unit.get_mut(id);
+            entry.set(
+                gimli::DW_AT_low_pc,
+                AttributeValue::Address(Address::Constant(start)),
+            );
+            entry.set(gimli::DW_AT_high_pc, AttributeValue::Udata(len));
+        };
+        set_range(&mut dwarf, root, 0x100, 0x80);
+        let inner = dwarf.unit.add(root, gimli::DW_TAG_subprogram);
+        let entry = dwarf.unit.get_mut(inner);
+        entry.set(gimli::DW_AT_name, AttributeValue::String(b"inner".to_vec()));
+        entry.set(
+            gimli::DW_AT_inline,
+            AttributeValue::Inline(gimli::DW_INL_inlined),
+        );
+        let outer = dwarf.unit.add(root, gimli::DW_TAG_subprogram);
+        dwarf
+            .unit
+            .get_mut(outer)
+            .set(gimli::DW_AT_name, AttributeValue::String(b"outer".to_vec()));
+        set_range(&mut dwarf, outer, 0x100, 0x80);
+        let inlined = dwarf.unit.add(outer, gimli::DW_TAG_inlined_subroutine);
+        dwarf
+            .unit
+            .get_mut(inlined)
+            .set(gimli::DW_AT_abstract_origin, AttributeValue::UnitRef(inner));
+        set_range(&mut dwarf, inlined, 0x110, 0x10);
+
+        let mut sections = Sections::new(EndianVec::new(gimli::LittleEndian));
+        dwarf.write(&mut sections).unwrap();
+        sections
+            .for_each(|id, data| {
+                let section = obj.add_section(
+                    Vec::new(),
+                    id.name().as_bytes().to_vec(),
+                    SectionKind::Debug,
+                );
+                obj.set_section_data(section, data.slice().to_vec(), 1);
+                Ok::<_, gimli::Error>(())
+            })
+            .unwrap();
+        obj.write().unwrap()
+    }
+
+    #[test]
+    fn symbol_lookup() {
+        let data = object_with_inlined_function();
+        let file = object::File::parse(&data[..]).unwrap();
+        let symbols = LibSymbols::from_object(&file);
+        let symbol = symbols.symbol_table.lookup(0x150).unwrap();
+        assert_eq!((symbol.name.as_str(), symbol.address), ("outer", 0x100));
+        assert_eq!(symbols.symbol_table.lookup(0x80), None);
+        assert_eq!(symbols.symbol_table.lookup(0x180), None);
+    }
+
+    #[test]
+    fn inline_frames() {
+        let data = object_with_inlined_function();
+        let file = object::File::parse(&data[..]).unwrap();
+        let base_avma = 0x7f00_0000;
+        let mut process_symbols = ProcessSymbols::default();
+        process_symbols.add_mapping(
+            base_avma..base_avma + 0x1000,
+            base_avma,
+            Rc::new(LibSymbols::from_object(&file)),
+        );
+
+        let mut profile = Profile::new(
+            "test",
+            ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+            SamplingInterval::from_millis(1),
+        );
+        let category_pair = profile.add_category("Other", CategoryColor::Gray).into();
+        let frame = |frame| FrameInfo {
+            frame,
+            category_pair,
+            flags: FrameFlags::empty(),
+        };
+        // The call from `outer` is in the inlined `inner`, and the sampled
+        // instruction is in `outer` itself.
+        let frames = [
+            frame(Frame::ReturnAddress(base_avma + 0x115)),
+            frame(Frame::InstructionPointer(base_avma + 0x150)),
+        ];
+        let frames = process_symbols.add_inline_frames(frames.into_iter(), &mut profile);
+        let inner = profile.intern_string("inner");
+        assert_eq!(
+            frames
+                .into_iter()
+                .map(|info| info.frame)
+                .collect::<Vec<_>>(),
+            [
+                Frame::ReturnAddress(base_avma + 0x115),
+                Frame::Label(inner),
+                Frame::InstructionPointer(base_avma + 0x150),
+            ]
+        );
+    }
+}
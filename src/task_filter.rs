@@ -0,0 +1,113 @@
+use regex::Regex;
+use std::collections::HashMap;
+
+/// Restricts the conversion to some processes and threads, so that system-wide
+/// recordings can be converted into a profile of just the interesting tasks.
+///
+/// A thread matches if its pid is one of the given pids, its tid is one of the
+/// given tids, and its name or the name of its process matches the comm regex.
+/// Filters which weren't given match every thread.
+///
+/// Mmap records only have a pid, and a process's mappings can be recorded
+/// before the thread which matches is created or renamed. So if the filter
+/// needs a matching thread, the converter keeps a process's mappings back until
+/// one of its threads matches.
+#[derive(Debug, Clone, Default)]
+pub struct TaskFilter {
+    pids: Vec<i32>,
+    tids: Vec<i32>,
+    comm: Option<Regex>,
+    /// The names of all threads, keyed by tid, if there's a comm regex. The
+    /// name of a process is the name of its main thread.
+    thread_names: HashMap<i32, String>,
+}
+
+impl TaskFilter {
+    pub fn new(pids: &[i32], tids: &[i32], comm: Option<&str>) -> Result<Self, regex::Error> {
+        Ok(Self {
+            pids: pids.to_vec(),
+            tids: tids.to_vec(),
+            comm: comm.map(Regex::new).transpose()?,
+            thread_names: HashMap::new(),
+        })
+    }
+
+    /// A thread was created. It has the same name as the thread which created it.
+    pub fn handle_fork(&mut self, tid: i32, parent_tid: i32) {
+        if self.comm.is_some() {
+            if let Some(name) = self.thread_names.get(&parent_tid).cloned() {
+                self.thread_names.insert(tid, name);
+            }
+        }
+    }
+
+    /// A thread got a new name, e.g. after an exec.
+    pub fn handle_name_update(&mut self, tid: i32, name: &str) {
+        if self.comm.is_some() {
+            self.thread_names.insert(tid, name.to_string());
+        }
+    }
+
+    pub fn matches_thread(&self, pid: i32, tid: i32) -> bool {
+        if !self.pids.is_empty() && !self.pids.contains(&pid) {
+            return false;
+        }
+        if !self.tids.is_empty() && !self.tids.contains(&tid) {
+            return false;
+        }
+        match &self.comm {
+            Some(comm) => [tid, pid].iter().any(|id| {
+                self.thread_names
+                    .get(id)
+                    .is_some_and(|name| comm.is_match(name))
+            }),
+            None => true,
+        }
+    }
+
+    /// Whether the process can match at all, judging by its pid alone.
+    pub fn matches_process(&self, pid: i32) -> bool {
+        self.pids.is_empty() || self.pids.contains(&pid)
+    }
+
+    /// Whether a process only matches once one of its threads matches, i.e.
+    /// whether there's a tid or comm filter.
+    pub fn filters_threads(&self) -> bool {
+        !self.tids.is_empty() || self.comm.is_some()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TaskFilter;
+
+    #[test]
+    fn filter_by_comm_and_tid() {
+        let mut filter = TaskFilter::new(&[], &[], Some("^fire")).unwrap();
+        filter.handle_name_update(10, "firefox");
+        filter.handle_fork(11, 10);
+        filter.handle_name_update(11, "Socket Thread");
+        filter.handle_name_update(20, "bash");
+        filter.handle_fork(21, 20);
+        // Threads match by their own name or by the name of their process.
+        assert!(filter.matches_thread(10, 10));
+        assert!(filter.matches_thread(10, 11));
+        assert!(!filter.matches_thread(20, 21));
+        // A process which execs into a matching binary starts matching.
+        filter.handle_fork(30, 20);
+        assert!(!filter.matches_thread(30, 30));
+        filter.handle_name_update(30, "firefox-bin");
+        assert!(filter.matches_thread(30, 30));
+
+        let filter = TaskFilter::new(&[10], &[11], None).unwrap();
+        assert!(filter.matches_thread(10, 11));
+        assert!(!filter.matches_thread(10, 10));
+        assert!(!filter.matches_thread(20, 11));
+        assert!(filter.matches_process(10));
+        assert!(!filter.matches_process(20));
+        assert!(filter.filters_threads());
+        assert!(!TaskFilter::new(&[10], &[], None).unwrap().filters_threads());
+
+        assert!(TaskFilter::new(&[], &[], Some("(")).is_err());
+    }
+}
@@ -0,0 +1,402 @@
+use linux_perf_data::{Feature, PerfFile};
+use std::collections::HashMap;
+
+/// The parts of the `HEADER_TRACING_DATA` feature section that we need: the
+/// format descriptions of the recorded tracepoints.
+///
+/// perf copies these descriptions from `/sys/kernel/tracing/events/*/*/format`
+/// at recording time. They describe the layout of each tracepoint's raw sample
+/// data, i.e. the name, type, offset and size of each field.
+#[derive(Debug, Clone, Default)]
+pub struct TracingData {
+    pub big_endian: bool,
+    /// The event formats, keyed by tracepoint ID.
+    pub event_formats: HashMap<u64, EventFormat>,
+}
+
+/// The format description of a single tracepoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventFormat {
+    pub system: String,
+    pub name: String,
+    pub id: u64,
+    pub fields: Vec<FieldFormat>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldFormat {
+    pub name: String,
+    pub kind: FieldKind,
+    pub offset: usize,
+    pub size: usize,
+    pub is_signed: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldKind {
+    /// An integer of 1, 2, 4 or 8 bytes.
+    Integer,
+    /// A pointer, which is only interesting as a hex address.
+    Pointer,
+    /// A fixed-size, nul-terminated `char` array, e.g. `char comm[16]`.
+    CharArray,
+    /// A `__data_loc char[]` string whose offset and length are stored in the field.
+    DynamicString,
+    /// A `__rel_loc char[]` string, like `DynamicString`, but with an offset
+    /// that's relative to the end of the field.
+    RelativeDynamicString,
+    /// Anything else, e.g. arrays of integers.
+    Other,
+}
+
+/// A decoded field value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldValue {
+    Signed(i64),
+    Unsigned(u64),
+    Pointer(u64),
+    String(String),
+    Bytes(Vec<u8>),
+}
+
+const TRACING_DATA_MAGIC: &[u8] = b"\x17\x08\x44tracing";
+
+impl TracingData {
+    pub fn from_perf_file(perf_file: &PerfFile) -> Option<Self> {
+        let data = perf_file.feature_section_data(Feature::TRACING_DATA)?;
+        Self::parse(data)
+    }
+
+    /// Parse the tracing data, as written by perf's `tracing_data_get`.
+    ///
+    /// The layout is: magic, version string, endianness byte, long size byte,
+    /// page size, the "header_page" and "header_event" sections, the ftrace
+    /// event formats, and then the formats of all other events, grouped by
+    /// system. We stop after the event formats; the remaining sections
+    /// (kallsyms, printk formats, saved cmdlines) are not needed.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let data = data.strip_prefix(TRACING_DATA_MAGIC)?;
+        let mut reader = Reader {
+            data,
+            big_endian: false,
+        };
+        let _version = reader.read_cstr()?;
+        reader.big_endian = reader.read_u8()? != 0;
+        let _long_size = reader.read_u8()?;
+        let _page_size = reader.read_u32()?;
+
+        for section_name in ["header_page", "header_event"] {
+            if reader.read_cstr()? != section_name {
+                return None;
+            }
+            let size = reader.read_u64()?;
+            reader.skip(size)?;
+        }
+
+        let ftrace_format_count = reader.read_u32()?;
+        for _ in 0..ftrace_format_count {
+            let size = reader.read_u64()?;
+            reader.skip(size)?;
+        }
+
+        let mut event_formats = HashMap::new();
+        let system_count = reader.read_u32()?;
+        for _ in 0..system_count {
+            let system = reader.read_cstr()?.to_string();
+            let format_count = reader.read_u32()?;
+            for _ in 0..format_count {
+                let size = reader.read_u64()?;
+                let format = reader.read_bytes(size)?;
+                let format = String::from_utf8_lossy(format);
+                if let Some(format) = EventFormat::parse(&system, &format) {
+                    event_formats.insert(format.id, format);
+                }
+            }
+        }
+
+        Some(Self {
+            big_endian: reader.big_endian,
+            event_formats,
+        })
+    }
+}
+
+impl EventFormat {
+    /// Parse the contents of a tracepoint's `format` file.
+    ///
+    /// ```plain
+    /// name: sched_wakeup
+    /// ID: 318
+    /// format:
+    ///     field:unsigned short common_type;    offset:0;    size:2;    signed:0;
+    ///     ...
+    ///     field:char comm[16];    offset:8;    size:16;    signed:0;
+    ///     field:pid_t pid;    offset:24;    size:4;    signed:1;
+    ///
+    /// print fmt: "comm=%s pid=%d ...", REC->comm, REC->pid, ...
+    /// ```
+    pub fn parse(system: &str, format: &str) -> Option<Self> {
+        let mut name = None;
+        let mut id = None;
+        let mut fields = Vec::new();
+        for line in format.lines() {
+            let line = line.trim();
+            if let Some(value) = line.strip_prefix("name:") {
+                name = Some(value.trim().to_string());
+            } else if let Some(value) = line.strip_prefix("ID:") {
+                id = value.trim().parse().ok();
+            } else if line.starts_with("field:") {
+                fields.extend(FieldFormat::parse(line));
+            }
+        }
+        Some(Self {
+            system: system.to_string(),
+            name: name?,
+            id: id?,
+            fields,
+        })
+    }
+
+    /// The fields which are specific to this tracepoint, i.e. all fields
+    /// except for the `common_*` fields which every tracepoint has.
+    pub fn own_fields(&self) -> impl Iterator<Item = &FieldFormat> {
+        self.fields
+            .iter()
+            .filter(|field| !field.name.starts_with("common_"))
+    }
+}
+
+impl FieldFormat {
+    /// Parse a line like
+    /// `field:char prev_comm[16]; offset:8; size:16; signed:0;`
+    fn parse(line: &str) -> Option<Self> {
+        let mut declaration = None;
+        let mut offset = None;
+        let mut size = None;
+        let mut is_signed = false;
+        for part in line.split(';') {
+            let (key, value) = match part.trim().split_once(':') {
+                Some(key_value) => key_value,
+                None => continue,
+            };
+            match key {
+                "field" => declaration = Some(value.trim()),
+                "offset" => offset = value.trim().parse().ok(),
+                "size" => size = value.trim().parse().ok(),
+                "signed" => is_signed = value.trim() == "1",
+                _ => {}
+            }
+        }
+        let declaration = declaration?;
+        let size = size?;
+
+        // The declaration is the type followed by the name, and the name can
+        // have an array suffix: "char prev_comm[16]", "__data_loc char[] name".
+        let (type_, name) = declaration.rsplit_once(|c: char| c.is_whitespace() || c == '*')?;
+        let (name, is_array) = match name.split_once('[') {
+            Some((name, _)) => (name, true),
+            None => (name, false),
+        };
+        let separator = &declaration[type_.len()..type_.len() + 1];
+        let kind = if type_.starts_with("__data_loc") {
+            FieldKind::DynamicString
+        } else if type_.starts_with("__rel_loc") {
+            FieldKind::RelativeDynamicString
+        } else if separator == "*" || type_.contains('*') {
+            FieldKind::Pointer
+        } else if is_array && type_.ends_with("char") {
+            FieldKind::CharArray
+        } else if !is_array && matches!(size, 1 | 2 | 4 | 8) {
+            FieldKind::Integer
+        } else {
+            FieldKind::Other
+        };
+        Some(Self {
+            name: name.to_string(),
+            kind,
+            offset: offset?,
+            size,
+            is_signed,
+        })
+    }
+
+    /// Decode this field's value from a tracepoint sample's raw data.
+    pub fn decode(&self, raw: &[u8], big_endian: bool) -> Option<FieldValue> {
+        let bytes = raw.get(self.offset..self.offset.checked_add(self.size)?)?;
+        let value = match self.kind {
+            FieldKind::Integer => {
+                let value = read_uint(bytes, big_endian);
+                if self.is_signed {
+                    // Sign-extend from the field size.
+                    let shift = 64 - 8 * self.size as u32;
+                    FieldValue::Signed(((value << shift) as i64) >> shift)
+                } else {
+                    FieldValue::Unsigned(value)
+                }
+            }
+            FieldKind::Pointer => FieldValue::Pointer(read_uint(bytes, big_endian)),
+            FieldKind::CharArray => FieldValue::String(string_until_nul(bytes)),
+            FieldKind::DynamicString | FieldKind::RelativeDynamicString => {
+                let location = read_uint(bytes, big_endian);
+                let mut start = (location & 0xffff) as usize;
+                let len = (location >> 16) as usize;
+                if self.kind == FieldKind::RelativeDynamicString {
+                    start += self.offset + self.size;
+                }
+                FieldValue::String(string_until_nul(raw.get(start..start.checked_add(len)?)?))
+            }
+            FieldKind::Other => FieldValue::Bytes(bytes.to_vec()),
+        };
+        Some(value)
+    }
+}
+
+fn read_uint(bytes: &[u8], big_endian: bool) -> u64 {
+    let mut value = 0;
+    if big_endian {
+        for byte in bytes {
+            value = (value << 8) | u64::from(*byte);
+        }
+    } else {
+        for byte in bytes.iter().rev() {
+            value = (value << 8) | u64::from(*byte);
+        }
+    }
+    value
+}
+
+fn string_until_nul(bytes: &[u8]) -> String {
+    let len = memchr::memchr(0, bytes).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn read_bytes(&mut self, len: u64) -> Option<&'a [u8]> {
+        let len = usize::try_from(len).ok()?;
+        if self.data.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(bytes)
+    }
+
+    fn skip(&mut self, len: u64) -> Option<()> {
+        self.read_bytes(len).map(|_| ())
+    }
+
+    fn read_u8(&mut self) -> Option<u8> {
+        Some(self.read_bytes(1)?[0])
+    }
+
+    fn read_u32(&mut self) -> Option<u32> {
+        Some(read_uint(self.read_bytes(4)?, self.big_endian) as u32)
+    }
+
+    fn read_u64(&mut self) -> Option<u64> {
+        Some(read_uint(self.read_bytes(8)?, self.big_endian))
+    }
+
+    fn read_cstr(&mut self) -> Option<&'a str> {
+        let len = memchr::memchr(0, self.data)?;
+        let s = std::str::from_utf8(&self.data[..len]).ok()?;
+        self.data = &self.data[len + 1..];
+        Some(s)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{EventFormat, FieldKind, FieldValue, TracingData};
+
+    const SCHED_WAKEUP_FORMAT: &str = "name: sched_wakeup
+ID: 318
+format:
+\tfield:unsigned short common_type;\toffset:0;\tsize:2;\tsigned:0;
+\tfield:unsigned char common_flags;\toffset:2;\tsize:1;\tsigned:0;
+\tfield:unsigned char common_preempt_count;\toffset:3;\tsize:1;\tsigned:0;
+\tfield:int common_pid;\toffset:4;\tsize:4;\tsigned:1;
+
+\tfield:char comm[16];\toffset:8;\tsize:16;\tsigned:0;
+\tfield:pid_t pid;\toffset:24;\tsize:4;\tsigned:1;
+\tfield:int prio;\toffset:28;\tsize:4;\tsigned:1;
+\tfield:const char * filename_ptr;\toffset:32;\tsize:8;\tsigned:0;
+\tfield:__data_loc char[] name;\toffset:40;\tsize:4;\tsigned:0;
+
+print fmt: \"comm=%s pid=%d prio=%d\", REC->comm, REC->pid, REC->prio
+";
+
+    #[test]
+    fn parse_and_decode_event_format() {
+        let format = EventFormat::parse("sched", SCHED_WAKEUP_FORMAT).unwrap();
+        assert_eq!(format.name, "sched_wakeup");
+        assert_eq!(format.id, 318);
+        let fields: Vec<_> = format
+            .own_fields()
+            .map(|field| (field.name.as_str(), field.kind))
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                ("comm", FieldKind::CharArray),
+                ("pid", FieldKind::Integer),
+                ("prio", FieldKind::Integer),
+                ("filename_ptr", FieldKind::Pointer),
+                ("name", FieldKind::DynamicString),
+            ]
+        );
+
+        let mut raw = vec![0; 44];
+        raw[8..12].copy_from_slice(b"bash");
+        raw[24..28].copy_from_slice(&1234i32.to_le_bytes());
+        raw[28..32].copy_from_slice(&(-5i32).to_le_bytes());
+        raw[32..40].copy_from_slice(&0xffff_8000_1234_5678u64.to_le_bytes());
+        raw[40..44].copy_from_slice(&((6u32 << 16) | 44).to_le_bytes());
+        raw.extend_from_slice(b"hello\0");
+        let values: Vec<_> = format
+            .own_fields()
+            .map(|field| field.decode(&raw, false).unwrap())
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                FieldValue::String("bash".into()),
+                FieldValue::Signed(1234),
+                FieldValue::Signed(-5),
+                FieldValue::Pointer(0xffff_8000_1234_5678),
+                FieldValue::String("hello".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_tracing_data() {
+        let mut data = b"\x17\x08\x44tracing0.6\0".to_vec();
+        data.push(0); // little endian
+        data.push(8); // long size
+        data.extend_from_slice(&4096u32.to_le_bytes());
+        for section_name in ["header_page", "header_event"] {
+            data.extend_from_slice(section_name.as_bytes());
+            data.push(0);
+            data.extend_from_slice(&3u64.to_le_bytes());
+            data.extend_from_slice(b"abc");
+        }
+        data.extend_from_slice(&0u32.to_le_bytes()); // no ftrace formats
+        data.extend_from_slice(&1u32.to_le_bytes()); // one system
+        data.extend_from_slice(b"sched\0");
+        data.extend_from_slice(&1u32.to_le_bytes()); // one format
+        data.extend_from_slice(&(SCHED_WAKEUP_FORMAT.len() as u64).to_le_bytes());
+        data.extend_from_slice(SCHED_WAKEUP_FORMAT.as_bytes());
+
+        let tracing_data = TracingData::parse(&data).unwrap();
+        assert!(!tracing_data.big_endian);
+        let format = &tracing_data.event_formats[&318];
+        assert_eq!(format.system, "sched");
+        assert_eq!(format.name, "sched_wakeup");
+    }
+}
@@ -0,0 +1,612 @@
+use debugid::{CodeId, DebugId};
+use framehop::{Module, ModuleSvmaInfo, ModuleUnwindData, TextByteData, Unwinder};
+use fxprof_processed_profile::LibraryInfo;
+use object::elf::PF_X;
+use object::{Object, ObjectSection, ObjectSegment, SectionKind, SegmentFlags};
+use profiler_get_symbols::{debug_id_for_object, DebugIdExt};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::binary_lookup::{BinaryLookup, ProcessRoot};
+use crate::options::Verbosity;
+use crate::symbolication::LibSymbols;
+
+/// Identifies a binary by its recorded path and build ID or, for binaries
+/// without a build ID, by the path of the file which was opened.
+type BinaryKey = (PathBuf, Option<Vec<u8>>);
+
+/// The separate debug files and the symbols of the binaries which were mapped
+/// so far. A binary is usually mapped into many processes, but its debug file
+/// only needs to be found once, which can mean reading and checksumming several
+/// large files, and its symbols and DWARF info only need to be loaded once.
+#[derive(Default)]
+pub struct BinaryCache {
+    /// `None` if the binary has no debug file.
+    debug_files: HashMap<BinaryKey, Option<Rc<DebugFile>>>,
+    symbols: HashMap<BinaryKey, Option<Rc<LibSymbols>>>,
+}
+
+impl BinaryCache {
+    /// Find the debug file of the binary, unless it was looked for before.
+    fn debug_file(
+        &mut self,
+        key: &BinaryKey,
+        debuglink: Option<(&str, u32)>,
+        binary_lookup: &BinaryLookup,
+        process_root: &ProcessRoot,
+        verbosity: Verbosity,
+    ) -> Option<Rc<DebugFile>> {
+        self.debug_files
+            .entry(key.clone())
+            .or_insert_with(|| {
+                let (path, build_id) = key;
+                open_debug_file(
+                    path,
+                    build_id.as_deref(),
+                    debuglink,
+                    binary_lookup,
+                    process_root,
+                    verbosity,
+                )
+                .map(Rc::new)
+            })
+            .clone()
+    }
+
+    /// The symbols of the binary, which are loaded the first time they're
+    /// needed.
+    fn symbols(
+        &mut self,
+        key: &BinaryKey,
+        load: impl FnOnce() -> Option<LibSymbols>,
+    ) -> Option<Rc<LibSymbols>> {
+        self.symbols
+            .entry(key.clone())
+            .or_insert_with(|| load().map(Rc::new))
+            .clone()
+    }
+}
+
+/// Find the binary which was mapped from `path` and map it into memory. If the
+/// build ID is known, files with a different build ID are skipped. Returns the
+/// path of the file which was opened, too.
+fn open_binary(
+    path: &Path,
+    build_id: Option<&[u8]>,
+    binary_lookup: &BinaryLookup,
+    process_root: &ProcessRoot,
+    verbosity: Verbosity,
+) -> Option<(PathBuf, memmap2::Mmap)> {
+    // Why the first candidate which exists was rejected. This is only printed
+    // if none of the candidates fits.
+    let mut rejection = None;
+    for candidate in binary_lookup.candidate_paths(path, build_id, Some(process_root)) {
+        let file = match std::fs::File::open(&candidate) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+        let mmap = match unsafe { memmap2::MmapOptions::new().map(&file) } {
+            Ok(mmap) => mmap,
+            Err(err) => {
+                rejection.get_or_insert(format!("Could not mmap file {:?}: {:?}", candidate, err));
+                continue;
+            }
+        };
+        let file = match object::File::parse(&mmap[..]) {
+            Ok(file) => file,
+            Err(_) => {
+                rejection.get_or_insert(format!("File {:?} has unrecognized format", candidate));
+                continue;
+            }
+        };
+        if let Some(build_id) = build_id {
+            match file.build_id().ok().flatten() {
+                Some(file_build_id) if build_id == file_build_id => {
+                    // Build IDs match. Good.
+                }
+                Some(file_build_id) => {
+                    rejection.get_or_insert(format!(
+                        "File {:?} has non-matching build ID {} (expected {})",
+                        candidate,
+                        CodeId::from_binary(file_build_id),
+                        CodeId::from_binary(build_id)
+                    ));
+                    continue;
+                }
+                None => {
+                    rejection.get_or_insert(format!(
+                        "File {:?} does not contain a build ID, but we expected it to have one",
+                        candidate
+                    ));
+                    continue;
+                }
+            }
+        }
+        drop(file);
+        if candidate != path && verbosity >= Verbosity::Verbose {
+            eprintln!("Using {:?} for {:?}", candidate, path);
+        }
+        return Some((candidate, mmap));
+    }
+    match rejection {
+        Some(rejection) if verbosity >= Verbosity::Normal => eprintln!("{}", rejection),
+        None if verbosity >= Verbosity::Verbose => eprintln!("Could not open file {:?}", path),
+        _ => {}
+    }
+    None
+}
+
+/// A separate debug file, with the symbols and the DWARF info which were
+/// stripped from a binary.
+struct DebugFile {
+    path: PathBuf,
+    mmap: memmap2::Mmap,
+}
+
+impl DebugFile {
+    fn symbols(&self) -> Option<LibSymbols> {
+        let file = object::File::parse(&self.mmap[..]).ok()?;
+        Some(LibSymbols::from_object(&file))
+    }
+
+    fn debug_frame_data(&self) -> Option<Vec<u8>> {
+        let file = object::File::parse(&self.mmap[..]).ok()?;
+        debug_frame_data(&file)
+    }
+}
+
+/// The contents of the `.debug_frame` section, which may be compressed.
+fn debug_frame_data<'data: 'file, 'file>(
+    file: &'file impl Object<'data, 'file>,
+) -> Option<Vec<u8>> {
+    let data = file
+        .section_by_name(".debug_frame")?
+        .uncompressed_data()
+        .ok()?;
+    (!data.is_empty()).then(|| data.into_owned())
+}
+
+/// Find the separate debug file of the binary which was mapped from `path`.
+/// A debug file must have the binary's build ID or, if the binary doesn't have
+/// one, the checksum from the binary's `.gnu_debuglink` section.
+fn open_debug_file(
+    path: &Path,
+    build_id: Option<&[u8]>,
+    debuglink: Option<(&str, u32)>,
+    binary_lookup: &BinaryLookup,
+    process_root: &ProcessRoot,
+    verbosity: Verbosity,
+) -> Option<DebugFile> {
+    let debuglink_name = debuglink.map(|(name, _)| name);
+    for candidate in
+        binary_lookup.debug_file_candidate_paths(path, build_id, debuglink_name, Some(process_root))
+    {
+        let file = match std::fs::File::open(&candidate) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+        let mmap = match unsafe { memmap2::MmapOptions::new().map(&file) } {
+            Ok(mmap) => mmap,
+            Err(_) => continue,
+        };
+        let matches = match (build_id, debuglink) {
+            (Some(build_id), _) => object::File::parse(&mmap[..])
+                .ok()
+                .and_then(|file| file.build_id().ok().flatten().map(|id| id == build_id))
+                .unwrap_or(false),
+            (None, Some((_, crc))) => {
+                let mut file_crc = flate2::Crc::new();
+                file_crc.update(&mmap[..]);
+                file_crc.sum() == crc
+            }
+            (None, None) => false,
+        };
+        if !matches {
+            if verbosity >= Verbosity::Verbose {
+                eprintln!("Debug file {:?} does not match {:?}", candidate, path);
+            }
+            continue;
+        }
+        if verbosity >= Verbosity::Verbose {
+            eprintln!("Using debug file {:?} for {:?}", candidate, path);
+        }
+        return Some(DebugFile {
+            path: candidate,
+            mmap,
+        });
+    }
+    None
+}
+
+/// The sections which the unwinder should use for the binary `file`.
+fn unwind_data<'data: 'file, 'file>(
+    file: &'file impl Object<'data, 'file>,
+    debug_file: Option<&DebugFile>,
+) -> ModuleUnwindData<Vec<u8>> {
+    fn section_data<'a>(section: &impl ObjectSection<'a>) -> Option<Vec<u8>> {
+        section
+            .data()
+            .ok()
+            .filter(|data| !data.is_empty())
+            .map(|data| data.to_owned())
+    }
+
+    match (
+        file.section_by_name(".eh_frame")
+            .as_ref()
+            .and_then(section_data),
+        file.section_by_name(".eh_frame_hdr")
+            .as_ref()
+            .and_then(section_data),
+    ) {
+        (Some(eh_frame), Some(eh_frame_hdr)) => {
+            ModuleUnwindData::EhFrameHdrAndEhFrame(eh_frame_hdr, eh_frame)
+        }
+        (Some(eh_frame), None) => ModuleUnwindData::EhFrame(eh_frame),
+        // Binaries which were compiled with -fno-asynchronous-unwind-tables
+        // only have .debug_frame, which may have been moved into the
+        // separate debug file. Its addresses are the same as the binary's.
+        (None, _) => match debug_frame_data(file)
+            .or_else(|| debug_file.and_then(DebugFile::debug_frame_data))
+        {
+            Some(debug_frame) => ModuleUnwindData::DebugFrame(debug_frame),
+            None => ModuleUnwindData::None,
+        },
+    }
+}
+
+fn compute_image_bias<'data: 'file, 'file>(
+    file: &'file impl Object<'data, 'file>,
+    mapping_start_file_offset: u64,
+    mapping_start_avma: u64,
+    mapping_size: u64,
+    verbosity: Verbosity,
+) -> Option<u64> {
+    let mapping_end_file_offset = mapping_start_file_offset + mapping_size;
+
+    // Find a text section which is fully contained in the mapping, and use it
+    // to map between file offsets and SVMAs.
+    if let Some((section_start_file_offset, section_start_svma)) = file
+        .sections()
+        .filter(|s| s.kind() == SectionKind::Text)
+        .find_map(|s| match s.file_range() {
+            Some((start_offset, size)) => {
+                let end_offset = start_offset + size;
+                if mapping_start_file_offset <= start_offset
+                    && end_offset <= mapping_end_file_offset
+                {
+                    Some((start_offset, s.address()))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        })
+    {
+        let section_start_avma =
+            mapping_start_avma + (section_start_file_offset - mapping_start_file_offset);
+        return Some(section_start_avma - section_start_svma);
+    }
+
+    // If no text section is contained in the mapping (for example because the
+    // file has no section headers, or because the mapping only covers part of
+    // the text section), fall back to the executable ELF segment which contains
+    // the start of the mapping. (For ELF files, `segments()` only returns PT_LOAD
+    // segments.)
+    for segment in file.segments() {
+        let is_executable = match segment.flags() {
+            SegmentFlags::Elf { p_flags } => p_flags & PF_X != 0,
+            _ => false,
+        };
+        if !is_executable {
+            continue;
+        }
+        let (segment_start_file_offset, segment_file_size) = segment.file_range();
+        let segment_end_file_offset = segment_start_file_offset + segment_file_size;
+        if segment_start_file_offset <= mapping_start_file_offset
+            && mapping_start_file_offset < segment_end_file_offset
+        {
+            let mapping_start_svma =
+                segment.address() + (mapping_start_file_offset - segment_start_file_offset);
+            return Some(mapping_start_avma - mapping_start_svma);
+        }
+    }
+
+    if verbosity >= Verbosity::Verbose {
+        eprintln!(
+            "Could not find a text section or executable segment for file offset range {:#x}..{:#x} (mapped at {:#x})",
+            mapping_start_file_offset, mapping_end_file_offset, mapping_start_avma
+        );
+    }
+    None
+}
+
+/// Tell the unwinder about this module, and also create a LibraryInfo
+/// so that the profile can be told about this module.
+///
+/// Returns the library info together with the module's base AVMA, i.e. the
+/// address that corresponds to relative address zero. If `symbolicate` is true
+/// and the binary could be opened, the library info has a symbol table, and
+/// the binary's symbols are returned as well, for looking up inlined functions.
+/// They are shared by all mappings of the binary.
+///
+/// The binary's separate debug file is looked for if the binary has a build ID
+/// or a `.gnu_debuglink` section, once per binary. Its path goes into the
+/// library info, and it's used for unwinding binaries without `.eh_frame` and
+/// for symbolication.
+///
+/// The unwinder needs to know about it in case we need to do DWARF stack
+/// unwinding - it needs to get the unwinding information from the binary.
+/// The profile needs to know about this module so that it can assign
+/// addresses in the stack to the right module and so that symbolication
+/// knows where to get symbols for this module.
+#[allow(clippy::too_many_arguments)]
+pub fn add_module_to_unwinder<U>(
+    unwinder: &mut U,
+    path: &str,
+    mapping_start_file_offset: u64,
+    mapping_start_avma: u64,
+    mapping_size: u64,
+    build_id: Option<&[u8]>,
+    binary_lookup: &BinaryLookup,
+    binary_cache: &mut BinaryCache,
+    process_root: &ProcessRoot,
+    verbosity: Verbosity,
+    symbolicate: bool,
+) -> Option<(LibraryInfo, u64, Option<Rc<LibSymbols>>)>
+where
+    U: Unwinder<Module = Module<Vec<u8>>>,
+{
+    let objpath = Path::new(path);
+
+    let binary = if path.starts_with('[') {
+        // E.g. [vdso], which isn't a file.
+        None
+    } else {
+        open_binary(objpath, build_id, binary_lookup, process_root, verbosity)
+    };
+
+    let mapping_end_avma = mapping_start_avma + mapping_size;
+
+    let code_id;
+    let debug_id;
+    let base_avma;
+    let debug_file;
+    let mut symbols = None;
+
+    if let Some((binary_path, mmap)) = binary {
+        let file = object::File::parse(&mmap[..]).ok()?;
+        let debuglink = file
+            .gnu_debuglink()
+            .ok()
+            .flatten()
+            .and_then(|(name, crc)| Some((std::str::from_utf8(name).ok()?, crc)));
+        // The build ID identifies the binary, but without one, the same path
+        // may have different binaries, e.g. in different containers.
+        let binary_key = match file.build_id().ok().flatten().or(build_id) {
+            Some(build_id) => (objpath.to_owned(), Some(build_id.to_owned())),
+            None => (binary_path, None),
+        };
+        // Separate debug files have the full symbol table and the DWARF info
+        // which were stripped from the binary.
+        debug_file = if binary_key.1.is_some() || debuglink.is_some() {
+            binary_cache.debug_file(
+                &binary_key,
+                debuglink,
+                binary_lookup,
+                process_root,
+                verbosity,
+            )
+        } else {
+            None
+        };
+
+        // Compute the AVMA that maps to SVMA zero. This is also called the "bias" of the
+        // image. On ELF it is also the image load address.
+        let base_svma = 0;
+        base_avma = compute_image_bias(
+            &file,
+            mapping_start_file_offset,
+            mapping_start_avma,
+            mapping_size,
+            verbosity,
+        )?;
+
+        let text = file.section_by_name(".text");
+        let text_env = file.section_by_name("text_env");
+        let eh_frame = file.section_by_name(".eh_frame");
+        let got = file.section_by_name(".got");
+        let eh_frame_hdr = file.section_by_name(".eh_frame_hdr");
+
+        let unwind_data = unwind_data(&file, debug_file.as_deref());
+
+        let text_data = if let Some(text_segment) = file
+            .segments()
+            .find(|segment| segment.name_bytes() == Ok(Some(b"__TEXT")))
+        {
+            let (start, size) = text_segment.file_range();
+            let address_range = base_avma + start..base_avma + start + size;
+            text_segment
+                .data()
+                .ok()
+                .map(|data| TextByteData::new(data.to_owned(), address_range))
+        } else if let Some(text_section) = &text {
+            if let Some((start, size)) = text_section.file_range() {
+                let address_range = base_avma + start..base_avma + start + size;
+                text_section
+                    .data()
+                    .ok()
+                    .map(|data| TextByteData::new(data.to_owned(), address_range))
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
+        fn svma_range<'a>(section: &impl ObjectSection<'a>) -> Range<u64> {
+            section.address()..section.address() + section.size()
+        }
+
+        let module = Module::new(
+            path.to_string(),
+            mapping_start_avma..mapping_end_avma,
+            base_avma,
+            ModuleSvmaInfo {
+                base_svma,
+                text: text.as_ref().map(svma_range),
+                text_env: text_env.as_ref().map(svma_range),
+                stubs: None,
+                stub_helper: None,
+                eh_frame: eh_frame.as_ref().map(svma_range),
+                eh_frame_hdr: eh_frame_hdr.as_ref().map(svma_range),
+                got: got.as_ref().map(svma_range),
+            },
+            unwind_data,
+            text_data,
+        );
+        unwinder.add_module(module);
+
+        debug_id = debug_id_for_object(&file)?;
+        code_id = file
+            .build_id()
+            .ok()
+            .flatten()
+            .map(|id| CodeId::from_binary(id).to_string());
+        if symbolicate {
+            symbols = binary_cache.symbols(&binary_key, || {
+                debug_file
+                    .as_deref()
+                    .and_then(DebugFile::symbols)
+                    .or_else(|| Some(LibSymbols::from_object(&file)))
+            });
+        }
+    } else {
+        // Without access to the binary file, make some guesses. We can't really
+        // know what the right base address is because we don't have the section
+        // information which lets us map between addresses and file offsets, but
+        // often svmas and file offsets are the same, so this is a reasonable guess.
+        base_avma = mapping_start_avma - mapping_start_file_offset;
+
+        // If we have a build ID, convert it to a debug_id and a code_id.
+        debug_id = build_id
+            .map(|id| DebugId::from_identifier(id, true)) // TODO: endian
+            .unwrap_or_default();
+        code_id = build_id.map(|id| CodeId::from_binary(id).to_string());
+
+        // The debug file can still be found by the build ID.
+        let binary_key = (objpath.to_owned(), build_id.map(ToOwned::to_owned));
+        debug_file = if build_id.is_some() {
+            binary_cache.debug_file(&binary_key, None, binary_lookup, process_root, verbosity)
+        } else {
+            None
+        };
+        if symbolicate {
+            symbols = binary_cache.symbols(&binary_key, || {
+                debug_file.as_deref().and_then(DebugFile::symbols)
+            });
+        }
+    }
+
+    let name = objpath
+        .file_name()
+        .map_or("<unknown>".into(), |f| f.to_string_lossy().to_string());
+    let lib = LibraryInfo {
+        debug_id,
+        code_id,
+        path: path.to_string(),
+        debug_path: match debug_file {
+            Some(debug_file) => debug_file.path.to_string_lossy().into_owned(),
+            None => path.to_string(),
+        },
+        debug_name: name.clone(),
+        name,
+        arch: None,
+        symbol_table: symbols.as_ref().map(|symbols| symbols.symbol_table.clone()),
+    };
+    Some((lib, base_avma, symbols))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{unwind_data, DebugFile};
+    use framehop::ModuleUnwindData;
+    use object::write::Object;
+    use object::{Architecture, BinaryFormat, Endianness, SectionKind};
+    use std::path::PathBuf;
+
+    /// An ELF file with the given sections.
+    fn elf_with_sections(sections: &[(&str, SectionKind, &[u8])]) -> Vec<u8> {
+        let mut obj = Object::new(BinaryFormat::Elf, Architecture::X86_64, Endianness::Little);
+        for (name, kind, data) in sections {
+            let section = obj.add_section(Vec::new(), name.as_bytes().to_vec(), *kind);
+            obj.append_section_data(section, data, 8);
+        }
+        obj.write().unwrap()
+    }
+
+    fn debug_file(data: &[u8]) -> DebugFile {
+        let mut mmap = memmap2::MmapMut::map_anon(data.len()).unwrap();
+        mmap.copy_from_slice(data);
+        DebugFile {
+            path: PathBuf::from("/usr/lib/debug/test.debug"),
+            mmap: mmap.make_read_only().unwrap(),
+        }
+    }
+
+    fn unwind_data_of(binary: &[u8], debug_file: Option<&DebugFile>) -> ModuleUnwindData<Vec<u8>> {
+        let file = object::File::parse(binary).unwrap();
+        unwind_data(&file, debug_file)
+    }
+
+    #[test]
+    fn eh_frame_is_preferred() {
+        let binary = elf_with_sections(&[
+            (".eh_frame", SectionKind::ReadOnlyData, b"eh_frame"),
+            (".eh_frame_hdr", SectionKind::ReadOnlyData, b"eh_frame_hdr"),
+            (".debug_frame", SectionKind::Debug, b"debug_frame"),
+        ]);
+        assert!(matches!(
+            unwind_data_of(&binary, None),
+            ModuleUnwindData::EhFrameHdrAndEhFrame(hdr, eh_frame) if hdr == b"eh_frame_hdr" && eh_frame == b"eh_frame"
+        ));
+
+        let binary = elf_with_sections(&[(".eh_frame", SectionKind::ReadOnlyData, b"eh_frame")]);
+        assert!(matches!(
+            unwind_data_of(&binary, None),
+            ModuleUnwindData::EhFrame(eh_frame) if eh_frame == b"eh_frame"
+        ));
+    }
+
+    #[test]
+    fn debug_frame_fallback() {
+        let debug = debug_file(&elf_with_sections(&[(
+            ".debug_frame",
+            SectionKind::Debug,
+            b"debug file's debug_frame",
+        )]));
+
+        // The binary's own .debug_frame comes first.
+        let binary = elf_with_sections(&[(".debug_frame", SectionKind::Debug, b"debug_frame")]);
+        assert!(matches!(
+            unwind_data_of(&binary, Some(&debug)),
+            ModuleUnwindData::DebugFrame(data) if data == b"debug_frame"
+        ));
+
+        // An empty .eh_frame doesn't count.
+        let binary = elf_with_sections(&[
+            (".eh_frame", SectionKind::ReadOnlyData, b""),
+            (".text", SectionKind::Text, &[0x90; 16]),
+        ]);
+        assert!(matches!(
+            unwind_data_of(&binary, Some(&debug)),
+            ModuleUnwindData::DebugFrame(data) if data == b"debug file's debug_frame"
+        ));
+        assert!(matches!(
+            unwind_data_of(&binary, None),
+            ModuleUnwindData::None
+        ));
+    }
+}
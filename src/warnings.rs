@@ -0,0 +1,63 @@
+use std::collections::BTreeMap;
+
+/// A recoverable problem with an individual record.
+///
+/// Records with these problems are skipped (or only partially used) instead of
+/// aborting the conversion. We count them and print a summary at the end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Warning {
+    /// The record could not be parsed.
+    UnparsableRecord,
+    /// A sample or context switch record did not have a pid or tid.
+    MissingPidOrTid,
+    /// A sample or context switch record did not have a timestamp.
+    MissingTimestamp,
+    /// A sample had user registers, but not all of the ones needed for unwinding.
+    MissingRegisters,
+    /// An mmap record had a path which was not valid UTF-8.
+    NonUtf8Path,
+    /// A mapping started so far from its library's base address that the
+    /// offset didn't fit into the profile's 32 bit relative addresses.
+    MappingOutOfRange,
+    /// Reading the records failed, and the rest of the file was ignored.
+    TruncatedRecordData,
+}
+
+impl Warning {
+    fn description(&self) -> &'static str {
+        match self {
+            Warning::UnparsableRecord => "Skipped records which could not be parsed",
+            Warning::MissingPidOrTid => "Skipped samples and context switches without a pid or tid",
+            Warning::MissingTimestamp => "Skipped samples and context switches without a timestamp",
+            Warning::MissingRegisters => {
+                "Skipped samples without the registers needed for unwinding"
+            }
+            Warning::NonUtf8Path => "Skipped mappings with a non-UTF-8 path",
+            Warning::MappingOutOfRange => {
+                "Skipped mappings too far from their library's base address"
+            }
+            Warning::TruncatedRecordData => {
+                "Stopped reading records after a read error (truncated file?)"
+            }
+        }
+    }
+}
+
+/// Counts the warnings which occurred during the conversion.
+#[derive(Debug, Clone, Default)]
+pub struct Warnings {
+    counts: BTreeMap<Warning, u64>,
+}
+
+impl Warnings {
+    pub fn add(&mut self, warning: Warning) {
+        *self.counts.entry(warning).or_default() += 1;
+    }
+
+    /// Print a summary of all warnings to stderr.
+    pub fn report(&self) {
+        for (warning, count) in &self.counts {
+            eprintln!("Warning: {} ({} times)", warning.description(), count);
+        }
+    }
+}